@@ -0,0 +1,65 @@
+//! Compares a block allocated `Context` against an `InlineContext` for shallow stacks
+//!
+//! run with `cargo bench --bench inline`
+
+use contextual::{Context, ContextExt, InlineContext};
+use std::{hint::black_box, time::Instant};
+
+const ITERATIONS: u32 = 1_000_000;
+
+thread_local! {
+    static BLOCKS: Context<u64> = const { Context::new_const::<16>() };
+    static INLINE: InlineContext<u64, 4> = const { InlineContext::new_inline_const::<16>() };
+}
+
+fn bench(name: &str, mut f: impl FnMut()) {
+    for _ in 0..ITERATIONS / 10 {
+        f();
+    }
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed();
+
+    println!("{:<32} {:>10.2?}/iter", name, elapsed / ITERATIONS);
+}
+
+fn nested<C: ContextExt<Item = u64>>(ctx: C, depth: u64) -> u64 {
+    ctx.scope(black_box(depth), |&value| {
+        if depth == 0 {
+            value
+        } else {
+            nested(ctx, depth - 1) + value
+        }
+    })
+}
+
+fn main() {
+    bench("new + push 3 (blocks)", || {
+        let ctx = Context::new(16);
+        black_box(nested(&ctx, 2));
+    });
+
+    bench("new + push 3 (inline)", || {
+        let ctx = InlineContext::<_, 4>::new_inline(16);
+        black_box(nested(&ctx, 2));
+    });
+
+    bench("thread local push 3 (blocks)", || {
+        black_box(nested(&BLOCKS, 2));
+    });
+
+    bench("thread local push 3 (inline)", || {
+        black_box(nested(&INLINE, 2));
+    });
+
+    bench("thread local push 32 (blocks)", || {
+        black_box(nested(&BLOCKS, 31));
+    });
+
+    bench("thread local push 32 (inline)", || {
+        black_box(nested(&INLINE, 31));
+    });
+}
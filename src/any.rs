@@ -0,0 +1,155 @@
+use crate::{Context, ContextError, ContextKey, StackGuard, StackPin};
+use std::{
+    any::{Any, TypeId},
+    cell::RefCell,
+    collections::HashMap,
+    num::NonZeroUsize,
+    ptr::NonNull,
+    thread::LocalKey,
+};
+
+type Contexts = HashMap<(TypeId, Option<&'static str>), Box<dyn Any>>;
+
+/// A collection of contexts, one for every type, and optionally for every name
+///
+/// The contexts are created the first time they're accessed, and can be used
+/// with the macros by naming the type
+///
+/// ```
+/// # use contextual::{get, push, AnyContext};
+/// thread_local! {
+///     static PROVIDED: AnyContext = AnyContext::new(16);
+/// }
+///
+/// struct Locale(&'static str);
+///
+/// push!(let _locale: PROVIDED = Locale("en"));
+/// push!(let _retries: PROVIDED = 3u32);
+///
+/// get!(let locale: PROVIDED as Locale);
+/// get!(let retries: PROVIDED as u32);
+/// assert_eq!(locale.0, "en");
+/// assert_eq!(*retries, 3);
+/// ```
+pub struct AnyContext {
+    contexts: RefCell<Contexts>,
+    block_capacity: NonZeroUsize,
+}
+
+impl AnyContext {
+    /// # Panics
+    ///
+    /// If `block_capacity` is zero
+    pub fn new(block_capacity: usize) -> Self {
+        Self {
+            contexts: RefCell::new(HashMap::new()),
+            block_capacity: NonZeroUsize::new(block_capacity).expect("The block capacity must be non-zero"),
+        }
+    }
+
+    fn entry<T: 'static>(&self, name: Option<&'static str>) -> &Context<T> {
+        let mut contexts = self.contexts.borrow_mut();
+        let ctx = contexts
+            .entry((TypeId::of::<T>(), name))
+            .or_insert_with(|| Box::new(Context::<T>::from_parts(self.block_capacity, usize::MAX)));
+        let ctx = ctx.downcast_ref::<Context<T>>().unwrap() as *const Context<T>;
+        // contexts are boxed and never removed until `self` is dropped
+        unsafe { &*ctx }
+    }
+
+    /// The context for values of type `T`
+    pub fn context<T: 'static>(&self) -> &Context<T> { self.entry(None) }
+
+    /// The context for values of type `T` with the given name, separate from
+    /// [`context`](Self::context) and from the contexts with other names
+    pub fn named<T: 'static>(&self, name: &'static str) -> &Context<T> { self.entry(Some(name)) }
+
+    /// Pushes a value onto the context for `T`, see [`Context::push`]
+    pub fn push<T: 'static>(&self, value: T) -> NonNull<T> { self.context().push(value) }
+
+    /// Pushes a value onto the context for `T` for the duration of `f`, see [`Context::scope`]
+    pub fn scope<T: 'static, R>(&self, value: T, f: impl FnOnce(&T) -> R) -> R { self.context().scope(value, f) }
+
+    /// Calls `f` with the top value of the context for `T`
+    ///
+    /// # Panics
+    ///
+    /// If the context is empty, or if the top value is mutably borrowed
+    pub fn get<T: 'static, R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        self.try_get(move |value| f(value.unwrap_or_else(|error| panic!("{}", error))))
+    }
+
+    /// Calls `f` with the top value of the context for `T`, or the reason it can't be accessed
+    pub fn try_get<T: 'static, R>(&self, f: impl FnOnce(Result<&T, ContextError>) -> R) -> R {
+        let stack_pin = unsafe { StackPin::new() };
+        let value = unsafe { StackGuard::from_ref(self.context::<T>(), &stack_pin) };
+        f(value.as_deref().map_err(|&error| error))
+    }
+
+    /// The number of values in the context for `T`, not counting the [`named`](Self::named) contexts
+    pub fn len<T: 'static>(&self) -> usize {
+        let contexts = self.contexts.borrow();
+        let ctx = contexts.get(&(TypeId::of::<T>(), None));
+        ctx.map_or(0, |ctx| ctx.downcast_ref::<Context<T>>().unwrap().len())
+    }
+
+    /// Returns true if there are no values in the context for `T`
+    pub fn is_empty<T: 'static>(&self) -> bool { self.len::<T>() == 0 }
+}
+
+impl<T: 'static> ContextKey<T, 0> for LocalKey<AnyContext> {
+    fn try_with_context<R>(&'static self, f: impl FnOnce(&Context<T>) -> R) -> Result<R, ContextError> {
+        self.try_with(move |any| f(any.context())).map_err(|_| ContextError::Destroyed)
+    }
+}
+
+#[test]
+fn any_context() {
+    thread_local! {
+        static PROVIDED: AnyContext = AnyContext::new(2);
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Locale(&'static str);
+
+    push!(let _locale: PROVIDED = Locale("en"));
+    push!(let _depth: PROVIDED = 0u32);
+
+    {
+        push!(let _locale: PROVIDED = Locale("fr"));
+        get_all!(let locales: PROVIDED as Locale);
+        assert!(locales.eq([Locale("fr"), Locale("en")].iter()));
+
+        get!(let depth: PROVIDED as u32);
+        assert_eq!(*depth, 0);
+    }
+
+    PROVIDED.with(|provided| {
+        assert_eq!(provided.len::<Locale>(), 1);
+        assert!(provided.is_empty::<String>());
+
+        assert_eq!(provided.get(|locale: &Locale| locale.0), "en");
+        assert_eq!(provided.try_get(|name: Result<&String, _>| name.cloned()), Err(ContextError::Empty));
+
+        provided.named::<u32>("retries").scope(3, |_| {
+            assert_eq!(provided.len::<u32>(), 1);
+            assert_eq!(provided.get(|depth: &u32| *depth), 0);
+            assert_eq!(provided.named::<u32>("retries").len(), 1);
+            assert!(provided.named::<u32>("timeout").is_empty());
+        });
+
+        let mut locale = Some(Locale("fr"));
+        provided.context().lend(&mut locale, || {
+            get_mut!(let locale: PROVIDED as Locale);
+            *locale = Locale("de");
+        });
+        assert_eq!(locale, Some(Locale("de")));
+    });
+
+    try_get!(let depth: PROVIDED as u32);
+    assert_eq!(depth, Ok(&0));
+    try_get!(let name: PROVIDED as String);
+    assert_eq!(name, Err(ContextError::Empty));
+    get!(let locale: PROVIDED as Locale);
+    assert_eq!(*locale, Locale("en"));
+}
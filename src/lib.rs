@@ -9,7 +9,13 @@ macro_rules! try_get {
         let stack_pin = unsafe { $crate::StackPin::new() };
         let stack_pin = &stack_pin;
         let $name = unsafe { $crate::StackGuard::new(&$context, stack_pin) };
-        let $name = $name.as_deref();
+        let $name = $name.as_deref().map_err(|&error| error);
+    };
+    (let $name:ident: $context:ident as $type:ty) => {
+        let stack_pin = unsafe { $crate::StackPin::new() };
+        let stack_pin = &stack_pin;
+        let $name = unsafe { $crate::StackGuard::<$type>::new(&$context, stack_pin) };
+        let $name = $name.as_deref().map_err(|&error| error);
     };
 }
 
@@ -17,56 +23,467 @@ macro_rules! try_get {
 macro_rules! get {
     (let $name:ident: $context:ident) => {
         $crate::try_get!(let $name: $context);
-        let $name = $name.expect("Tried to get from an empty context");
+        let $name = $name.unwrap_or_else(|error| panic!("{}", error));
+    };
+    (let $name:ident: $context:ident as $type:ty) => {
+        $crate::try_get!(let $name: $context as $type);
+        let $name = $name.unwrap_or_else(|error| panic!("{}", error));
+    };
+}
+
+#[macro_export]
+macro_rules! try_get_mut {
+    (let $name:ident: $context:ident) => {
+        let stack_pin = unsafe { $crate::StackPin::new() };
+        let stack_pin = &stack_pin;
+        let mut $name = unsafe { $crate::StackGuardMut::new(&$context, stack_pin) };
+        let $name = $name.as_deref_mut().map_err(|&mut error| error);
+    };
+    (let $name:ident: $context:ident as $type:ty) => {
+        let stack_pin = unsafe { $crate::StackPin::new() };
+        let stack_pin = &stack_pin;
+        let mut $name = unsafe { $crate::StackGuardMut::<$type>::new(&$context, stack_pin) };
+        let $name = $name.as_deref_mut().map_err(|&mut error| error);
+    };
+}
+
+#[macro_export]
+macro_rules! get_mut {
+    (let $name:ident: $context:ident) => {
+        $crate::try_get_mut!(let $name: $context);
+        let $name = $name.unwrap_or_else(|error| panic!("{}", error));
+    };
+    (let $name:ident: $context:ident as $type:ty) => {
+        $crate::try_get_mut!(let $name: $context as $type);
+        let $name = $name.unwrap_or_else(|error| panic!("{}", error));
+    };
+}
+
+#[macro_export]
+macro_rules! get_all {
+    (let $name:ident: $context:ident) => {
+        let stack_pin = unsafe { $crate::StackPin::new() };
+        let stack_pin = &stack_pin;
+        let mut $name = unsafe { $crate::Iter::new(&$context, stack_pin) };
+        let $name = &mut $name;
+    };
+    (let $name:ident: $context:ident as $type:ty) => {
+        let stack_pin = unsafe { $crate::StackPin::new() };
+        let stack_pin = &stack_pin;
+        let mut $name = unsafe { $crate::Iter::<$type>::new(&$context, stack_pin) };
+        let $name = &mut $name;
+    };
+}
+
+/// Like `push!`, but binds a `Result` which holds the value if it couldn't be pushed
+///
+/// ```
+/// # use contextual::{try_push, Context, OverflowPolicy, PushError};
+/// thread_local! {
+///     static DEPTH: Context<u32> = Context::new(16).with_max_depth(1, OverflowPolicy::Panic);
+/// }
+///
+/// try_push!(let first: DEPTH = 0);
+/// try_push!(let second: DEPTH = 1);
+/// assert_eq!(first, Ok(&0));
+/// assert_eq!(second, Err((1, PushError::DepthExceeded { max_depth: 1 })));
+/// ```
+#[macro_export]
+macro_rules! try_push {
+    (let $name:ident: $context:ident = $value:expr) => {
+        let value = $value;
+        let stack_pin = unsafe { $crate::StackPin::new() };
+        let stack_pin = &stack_pin;
+        let item = unsafe { $crate::Item::try_new(&$context, stack_pin, value) };
+        let $name = match item {
+            Ok(ref item) => Ok(&**item),
+            Err(error) => Err(error),
+        };
     };
 }
 
 #[macro_export]
 macro_rules! push {
     (let $name:ident: $context:ident = $value:expr) => {
+        let value = $value;
         let stack_pin = unsafe { $crate::StackPin::new() };
         let stack_pin = &stack_pin;
-        let $name = unsafe { $crate::Item::new(&$context, stack_pin, $value) };
+        let $name = unsafe { $crate::Item::new(&$context, stack_pin, value) };
         let $name = &*$name;
     };
 }
 
+mod any;
+mod local;
+pub mod mdc;
+mod snapshot;
+
+#[cfg(feature = "rayon")]
+pub mod rayon;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+#[cfg(feature = "tracing")]
+pub mod tracing;
+
+pub use any::AnyContext;
+pub use local::{LocalContext, Pushed};
+pub use snapshot::{spawn, Capture, Captured, Install, InstallFuture, Snapshot};
+
 use std::{
-    cell::{Cell, UnsafeCell},
+    any::Any,
+    cell::{Cell, RefCell, UnsafeCell},
+    collections::HashMap,
+    fmt,
+    future::Future,
     marker::PhantomData,
     mem::MaybeUninit,
     num::NonZeroUsize,
+    pin::Pin,
     ptr::NonNull,
+    task::{self, Poll},
     thread::LocalKey,
 };
 
-pub trait ContextExt: Sized {
+pub trait ContextExt: Copy {
     type Item;
     fn len(self) -> usize;
     fn is_empty(self) -> bool { self.len() == 0 }
     fn push(self, value: Self::Item);
+    fn scope<R>(self, value: Self::Item, f: impl FnOnce(&Self::Item) -> R) -> R;
+    fn lend<R>(self, value: &mut Option<Self::Item>, f: impl FnOnce() -> R) -> R;
+    fn with_modified<R>(
+        self,
+        modify: impl FnOnce(&Self::Item) -> Self::Item,
+        f: impl FnOnce(&Self::Item) -> R,
+    ) -> R;
+
+    fn snapshot(self) -> Snapshot<Self::Item>
+    where
+        Self::Item: Clone;
+    fn restore<R>(self, snapshot: &Snapshot<Self::Item>, f: impl FnOnce() -> R) -> R
+    where
+        Self::Item: Clone;
+
+    fn scope_future<F: Future>(self, value: Self::Item, future: F) -> ContextFuture<Self, Self::Item, F> {
+        ContextFuture {
+            context: self,
+            value: Some(value),
+            future,
+        }
+    }
 }
 
-impl<T> ContextExt for &Context<T> {
+impl<T, const N: usize> ContextExt for &Context<T, N> {
     type Item = T;
 
     fn len(self) -> usize { self.len() }
 
     fn push(self, value: Self::Item) { self.push(value); }
+
+    fn scope<R>(self, value: Self::Item, f: impl FnOnce(&Self::Item) -> R) -> R { self.scope(value, f) }
+
+    fn lend<R>(self, value: &mut Option<Self::Item>, f: impl FnOnce() -> R) -> R { self.lend(value, f) }
+
+    fn with_modified<R>(
+        self,
+        modify: impl FnOnce(&Self::Item) -> Self::Item,
+        f: impl FnOnce(&Self::Item) -> R,
+    ) -> R {
+        self.with_modified(modify, f)
+    }
+
+    fn snapshot(self) -> Snapshot<T>
+    where
+        T: Clone,
+    {
+        self.snapshot()
+    }
+
+    fn restore<R>(self, snapshot: &Snapshot<T>, f: impl FnOnce() -> R) -> R
+    where
+        T: Clone,
+    {
+        self.restore(snapshot, f)
+    }
 }
 
-impl<T> ContextExt for &'static LocalKey<Context<T>> {
-    type Item = T;
+// `ContextKey` can't be used for a blanket impl, since a key may provide contexts for more than one type
+macro_rules! key_context_ext {
+    ($($key:ty),*) => {$(
+        impl<T: 'static, const N: usize> ContextExt for &'static $key {
+            type Item = T;
+
+            fn len(self) -> usize { self.try_with_context(|x| x.len()).unwrap_or(0) }
+
+            fn push(self, value: Self::Item) { let _ = self.try_with_context(|x| x.push(value)); }
+
+            fn scope<R>(self, value: Self::Item, f: impl FnOnce(&Self::Item) -> R) -> R {
+                with_context_or(self, (value, f), |x, (value, f)| x.scope(value, f), |(value, f)| f(&value))
+            }
+
+            fn lend<R>(self, value: &mut Option<Self::Item>, f: impl FnOnce() -> R) -> R {
+                with_context_or(self, (value, f), |x, (value, f)| x.lend(value, f), |(_, f)| f())
+            }
+
+            fn with_modified<R>(
+                self,
+                modify: impl FnOnce(&Self::Item) -> Self::Item,
+                f: impl FnOnce(&Self::Item) -> R,
+            ) -> R {
+                self.with_context(move |x| x.with_modified(modify, f))
+            }
+
+            fn snapshot(self) -> Snapshot<T>
+            where
+                T: Clone,
+            {
+                self.try_with_context(Context::snapshot).unwrap_or_default()
+            }
+
+            fn restore<R>(self, snapshot: &Snapshot<T>, f: impl FnOnce() -> R) -> R
+            where
+                T: Clone,
+            {
+                with_context_or(self, f, |x, f| x.restore(snapshot, f), |f| f())
+            }
+        }
+    )*};
+}
+
+key_context_ext!(LocalKey<Context<T, N>>, SyncContext<T, N>);
+
+/// A handle to a context which has a separate stack on every thread
+///
+/// This is what the macros use to find the current thread's stack
+pub trait ContextKey<T, const N: usize> {
+    /// # Panics
+    ///
+    /// If the current thread's context has been destroyed
+    fn with_context<R>(&'static self, f: impl FnOnce(&Context<T, N>) -> R) -> R {
+        self.try_with_context(f).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Like [`with_context`](Self::with_context), but returns [`ContextError::Destroyed`]
+    /// instead of panicking if the current thread's context has been destroyed
+    fn try_with_context<R>(&'static self, f: impl FnOnce(&Context<T, N>) -> R) -> Result<R, ContextError>;
+
+    /// Identifies the key on the current thread, so tasks can find their own copy of the context
+    #[cfg(feature = "tokio")]
+    #[doc(hidden)]
+    fn task_key(&'static self) -> Option<usize> { None }
+}
+
+/// Calls `f` with the current thread's context, or `destroyed` if it has been destroyed
+///
+/// This is how pushes become no-ops while thread locals are being destroyed
+fn with_context_or<K, T, A, R, const N: usize>(
+    key: &'static K,
+    args: A,
+    f: impl FnOnce(&Context<T, N>, A) -> R,
+    destroyed: impl FnOnce(A) -> R,
+) -> R
+where
+    K: ContextKey<T, N> + ?Sized,
+{
+    let mut args = Some(args);
+    match key.try_with_context(|ctx| f(ctx, args.take().unwrap())) {
+        Ok(value) => value,
+        Err(_) => destroyed(args.take().unwrap()),
+    }
+}
+
+/// The reasons a context value can't be accessed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ContextError {
+    /// Nothing has been pushed onto the context, and it has no default value
+    Empty,
+    /// The context is a thread local which has been destroyed, or is being destroyed
+    Destroyed,
+    /// The top value of the context is mutably borrowed
+    BorrowedMutably,
+    /// The top value of the context is borrowed, so it can't be mutably borrowed
+    Borrowed,
+}
+
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Empty => "Tried to get from an empty context",
+            Self::Destroyed => "Tried to access a context which has been destroyed",
+            Self::BorrowedMutably => "Tried to get from a mutably borrowed context",
+            Self::Borrowed => "Tried to mutably borrow a context value which is already borrowed",
+        })
+    }
+}
+
+impl std::error::Error for ContextError {}
+
+impl<T, const N: usize> ContextKey<T, N> for LocalKey<Context<T, N>> {
+    fn try_with_context<R>(&'static self, f: impl FnOnce(&Context<T, N>) -> R) -> Result<R, ContextError> {
+        #[cfg(feature = "tokio")]
+        let f = match tokio::with_task_context(self, f) {
+            Ok(value) => return Ok(value),
+            Err(f) => f,
+        };
+
+        self.try_with(f).map_err(|_| ContextError::Destroyed)
+    }
+
+    // different uses of a `thread_local!` may not have the same address, but they always have the same storage
+    #[cfg(feature = "tokio")]
+    fn task_key(&'static self) -> Option<usize> { self.try_with(|ctx| ctx as *const Context<T, N> as usize).ok() }
+}
+
+impl<T: 'static, const N: usize> ContextKey<T, N> for SyncContext<T, N> {
+    fn try_with_context<R>(&'static self, f: impl FnOnce(&Context<T, N>) -> R) -> Result<R, ContextError> {
+        #[cfg(feature = "tokio")]
+        let f = match tokio::with_task_context(self, f) {
+            Ok(value) => return Ok(value),
+            Err(f) => f,
+        };
+
+        let ctx = SYNC_CONTEXTS.try_with(|contexts| {
+            let mut contexts = contexts.borrow_mut();
+            let ctx = contexts
+                .entry(self as *const Self as usize)
+                .or_insert_with(|| Box::new(Context::<T, N>::from_parts(self.block_capacity, usize::MAX)));
+            let ctx = ctx.downcast_ref::<Context<T, N>>().unwrap();
+            // contexts are boxed and never removed until the thread exits
+            ctx as *const Context<T, N>
+        });
+
+        let ctx = ctx.map_err(|_| ContextError::Destroyed)?;
+        Ok(f(unsafe { &*ctx }))
+    }
+
+    #[cfg(feature = "tokio")]
+    fn task_key(&'static self) -> Option<usize> { Some(self as *const Self as usize) }
+}
 
-    fn len(self) -> usize { self.with(|x| x.len()) }
+thread_local! {
+    static SYNC_CONTEXTS: RefCell<HashMap<usize, Box<dyn Any>>> = RefCell::new(HashMap::new());
+}
 
-    fn push(self, value: Self::Item) { self.with(|x| x.push(value)); }
+/// A context that can be stored in a `static`, which transparently keeps a
+/// separate stack for every thread
+///
+/// ```
+/// # use contextual::{get, push, SyncContext};
+/// static REQUEST_ID: SyncContext<u64> = SyncContext::new(16);
+///
+/// push!(let _id: REQUEST_ID = 10);
+/// get!(let id: REQUEST_ID);
+/// assert_eq!(*id, 10);
+/// ```
+pub struct SyncContext<T, const N: usize = 0> {
+    block_capacity: NonZeroUsize,
+    value: PhantomData<fn() -> T>,
 }
 
-pub struct Context<T> {
+pub struct Context<T, const N: usize = 0> {
+    inline: UnsafeCell<MaybeUninit<[T; N]>>,
     blocks: UnsafeCell<Vec<*mut T>>,
     block_capacity: NonZeroUsize,
+    max_spare_blocks: usize,
     len: Cell<usize>,
+    /// values below this index are hidden by [`Context::restore`]
+    base: Cell<usize>,
+    /// the value of [`Context::top`] when the context is empty
+    default: Option<UnsafeCell<T>>,
+    /// set by [`Context::restore`], the default's borrow state is hidden along with the original values
+    default_hidden: Cell<bool>,
+    max_depth_seen: Cell<usize>,
+    /// pushing beyond this depth is handled by `overflow_policy`
+    max_depth: usize,
+    overflow_policy: OverflowPolicy,
+    borrow: BorrowState,
+}
+
+/// What happens when a value is pushed onto a context which is already at its max depth
+///
+/// [`Context::try_push`] always returns an error instead
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    Panic,
+    Abort,
+}
+
+/// The reasons [`Context::try_push`] can fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PushError {
+    /// The context is already at its max depth
+    DepthExceeded { max_depth: usize },
+    /// A new block couldn't be allocated
+    Alloc(AllocError),
+}
+
+/// The allocator couldn't allocate a new block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DepthExceeded { max_depth } => write!(f, "Tried to push onto a context at its max depth of {}", max_depth),
+            Self::Alloc(error) => error.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str("Failed to allocate a block for a context") }
+}
+
+impl std::error::Error for PushError {}
+
+impl std::error::Error for AllocError {}
+
+impl From<AllocError> for PushError {
+    fn from(error: AllocError) -> Self { Self::Alloc(error) }
+}
+
+/// How many values are stored in one block of a context
+///
+/// Created by [`Context::occupancy`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Occupancy {
+    /// the number of values in the block
+    pub len: usize,
+    /// the number of values the block can hold
+    pub capacity: usize,
+    /// whether this is the inline storage of an [`InlineContext`], rather than a heap allocated block
+    pub inline: bool,
+}
+
+struct BorrowState {
+    /// the number of shared borrows of the top value, or `-1` if it's mutably borrowed
+    top: Cell<isize>,
+    /// the number of values in the context which are mutably borrowed
+    mut_borrows: Cell<usize>,
+}
+
+/// A context which stores the first `N` values inline, and only allocates
+/// blocks once more than `N` values are pushed
+pub type InlineContext<T, const N: usize> = Context<T, N>;
+
+/// A future which has a value pushed onto a context whenever it is polled
+///
+/// Created by [`ContextExt::scope_future`]
+pub struct ContextFuture<C, T, F> {
+    context: C,
+    value: Option<T>,
+    future: F,
+}
+
+/// Restores the original top value of a context when dropped
+///
+/// Created by [`Context::replace_top`]
+pub struct ReplaceTop<'a, T, const N: usize = 0> {
+    ctx: &'a Context<T, N>,
+    len: usize,
+    value: Option<T>,
 }
 
 #[doc(hidden)]
@@ -75,13 +492,37 @@ pub struct StackPin(());
 #[doc(hidden)]
 pub struct StackGuard<'a, T> {
     value: NonNull<T>,
+    borrow: &'a BorrowState,
+    stack_pin: PhantomData<&'a mut &'a StackPin>,
+}
+
+#[doc(hidden)]
+pub struct StackGuardMut<'a, T> {
+    value: NonNull<T>,
+    borrow: &'a BorrowState,
+    shared: isize,
+    stack_pin: PhantomData<&'a mut &'a StackPin>,
+}
+
+/// An iterator over all values in a context, from the top of the stack to the bottom
+///
+/// Created by [`Context::iter`] or [`get_all!`]
+pub struct Iter<'a, T, const N: usize = 0> {
+    // `None` if the context has been destroyed, then the iterator is empty
+    ctx: Option<&'a Context<T, N>>,
+    front: usize,
+    back: usize,
+    borrow: Option<&'a BorrowState>,
     stack_pin: PhantomData<&'a mut &'a StackPin>,
 }
 
 #[doc(hidden)]
-pub struct Item<'ctx, 'a, T> {
+pub struct Item<'ctx, 'a, T, const N: usize = 0> {
     value: NonNull<T>,
-    ctx: &'ctx Context<T>,
+    ctx: &'ctx Context<T, N>,
+    borrow: isize,
+    /// the context which owns `ctx`, if the item's context had been destroyed when it was pushed
+    detached: Option<NonNull<Context<T, N>>>,
     stack_pin: PhantomData<&'a mut &'a StackPin>,
 }
 
@@ -89,7 +530,46 @@ thread_local! {
     static CONTEXT: Context<i32> = Context::new(16);
 }
 
-impl<T> Drop for Context<T> {
+/// Formats the values in the context from the top of the stack to the bottom
+impl<T: fmt::Debug, const N: usize> fmt::Debug for Context<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Values<'a, T, const N: usize>(&'a Context<T, N>);
+        struct Borrowed;
+
+        impl<T: fmt::Debug, const N: usize> fmt::Debug for Values<'_, T, N> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let stack_pin = unsafe { StackPin::new() };
+                let values = unsafe { Iter::from_ref(self.0, &stack_pin) };
+                f.debug_list().entries(values).finish()
+            }
+        }
+
+        impl fmt::Debug for Borrowed {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str("<mutably borrowed>") }
+        }
+
+        let mut f = f.debug_struct("Context");
+
+        // formatting a value while it's mutably borrowed would alias the mutable reference
+        if self.borrow.mut_borrows.get() == 0 && self.borrow.top.get() >= 0 {
+            f.field("values", &Values(self));
+        } else {
+            f.field("values", &Borrowed);
+        }
+
+        if self.is_empty() && self.top().is_some() {
+            let stack_pin = unsafe { StackPin::new() };
+            match unsafe { StackGuard::from_ref(self, &stack_pin) } {
+                Ok(default) => f.field("default", &*default),
+                Err(_) => f.field("default", &Borrowed),
+            };
+        }
+
+        f.finish()
+    }
+}
+
+impl<T, const N: usize> Drop for Context<T, N> {
     fn drop(&mut self) {
         struct DropContext<'a, I: Iterator<Item = (*mut T, (usize, usize))>, T> {
             blocks: &'a mut I,
@@ -104,6 +584,8 @@ impl<T> Drop for Context<T> {
         }
 
         let len = self.len.get();
+        let inline_len = len.min(N);
+        let len = len - inline_len;
         let capacity = self.block_capacity.get();
 
         let init_blocks = len / capacity;
@@ -119,6 +601,11 @@ impl<T> Drop for Context<T> {
 
         let on_panic = DropContext { blocks: &mut blocks };
 
+        unsafe {
+            let inline = self.inline.get().cast::<T>();
+            core::ptr::slice_from_raw_parts_mut(inline, inline_len).drop_in_place();
+        }
+
         drop(DropContext {
             blocks: on_panic.blocks,
         });
@@ -127,137 +614,1008 @@ impl<T> Drop for Context<T> {
     }
 }
 
+impl<C, T, F: Unpin> Unpin for ContextFuture<C, T, F> {}
+
+impl<C: ContextExt<Item = T> + Copy, T, F: Future> Future for ContextFuture<C, T, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        // the value is never pinned, it is moved in and out of the context on every poll
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        this.context.lend(&mut this.value, move || future.poll(cx))
+    }
+}
+
 impl StackPin {
+    /// # Safety
+    ///
+    /// The `StackPin` must be stored in a local variable and only ever be used by reference
     pub unsafe fn new() -> Self { Self(()) }
 }
 
+impl BorrowState {
+    const fn new() -> Self {
+        Self {
+            top: Cell::new(0),
+            mut_borrows: Cell::new(0),
+        }
+    }
+
+    /// Sets the borrow state for a newly pushed value, and returns the state of the previous top value
+    fn enter(&self, shared: isize) -> isize { self.top.replace(shared) }
+
+    /// Restores the borrow state of the previous top value
+    fn exit(&self, borrow: isize) { self.top.set(borrow) }
+
+    fn borrow(&self) {
+        let top = self.top.get();
+        assert!(top >= 0, "Tried to get from a mutably borrowed context");
+        self.top.set(top + 1);
+    }
+
+    fn release(&self) { self.top.set(self.top.get() - 1) }
+
+    fn borrow_all(&self) {
+        assert!(self.mut_borrows.get() == 0, "Tried to get from a mutably borrowed context");
+        self.borrow();
+    }
+
+    /// Mutably borrows the top value, if only `shared` borrows that can't be used
+    /// while this borrow is active exist
+    fn borrow_mut(&self, shared: isize) {
+        if let Err(error) = self.try_borrow_mut(shared) {
+            panic!("{}", error)
+        }
+    }
+
+    fn try_borrow_mut(&self, shared: isize) -> Result<(), ContextError> {
+        match self.top.get() {
+            top if top == shared => {
+                self.top.set(-1);
+                self.mut_borrows.set(self.mut_borrows.get() + 1);
+                Ok(())
+            }
+            top if top < 0 => Err(ContextError::BorrowedMutably),
+            _ => Err(ContextError::Borrowed),
+        }
+    }
+
+    fn assert_unborrowed(&self) {
+        assert!(
+            self.top.get() == 0,
+            "Tried to replace a context value which is already borrowed"
+        );
+    }
+
+    fn release_mut(&self, shared: isize) {
+        self.top.set(shared);
+        self.mut_borrows.set(self.mut_borrows.get() - 1);
+    }
+}
+
 impl<'a, T> StackGuard<'a, T> {
-    pub unsafe fn from_ref(context: &Context<T>, _: &'a StackPin) -> Option<Self> {
-        Some(Self {
-            value: context.top()?,
+    /// # Safety
+    ///
+    /// The `StackPin` must not outlive the current stack frame, and no value may be
+    /// popped from `context` while the returned guard is alive
+    ///
+    /// # Errors
+    ///
+    /// If the context is empty, or the top value is mutably borrowed
+    pub unsafe fn from_ref<const N: usize>(context: &Context<T, N>, _: &'a StackPin) -> Result<Self, ContextError> {
+        let value = context.top().ok_or(ContextError::Empty)?;
+        if context.borrow.top.get() < 0 {
+            return Err(ContextError::BorrowedMutably)
+        }
+
+        let borrow = &*(&context.borrow as *const BorrowState);
+        borrow.borrow();
+        Ok(Self {
+            value,
+            borrow,
             stack_pin: PhantomData,
         })
     }
 
     #[doc(hidden)]
-    pub unsafe fn new(context: &'static LocalKey<Context<T>>, pin: &'a StackPin) -> Option<Self> {
-        context.with(move |ctx| Self::from_ref(ctx, pin))
+    pub unsafe fn new<K: ContextKey<T, N>, const N: usize>(
+        context: &'static K,
+        pin: &'a StackPin,
+    ) -> Result<Self, ContextError> {
+        context.try_with_context(move |ctx| Self::from_ref(ctx, pin))?
     }
 }
 
-impl<'ctx, 'a, T> Item<'ctx, 'a, T> {
-    pub unsafe fn from_ref(ctx: &'ctx Context<T>, _: &'a StackPin, value: T) -> Self {
-        Self {
-            value: ctx.push(value),
-            ctx,
+impl<'a, T> StackGuardMut<'a, T> {
+    /// # Safety
+    ///
+    /// The `StackPin` must not outlive the current stack frame, and no value may be
+    /// popped from `context` while the returned guard is alive
+    ///
+    /// # Errors
+    ///
+    /// If the context is empty, or the top value is already borrowed
+    pub unsafe fn from_ref<const N: usize>(context: &Context<T, N>, _: &'a StackPin) -> Result<Self, ContextError> {
+        let value = context.top().ok_or(ContextError::Empty)?;
+        let borrow = &*(&context.borrow as *const BorrowState);
+        borrow.try_borrow_mut(0)?;
+        Ok(Self {
+            value,
+            borrow,
+            shared: 0,
             stack_pin: PhantomData,
-        }
+        })
     }
 
     #[doc(hidden)]
-    pub unsafe fn new(context: &'static LocalKey<Context<T>>, pin: &'a StackPin, value: T) -> Self {
-        context.with(move |ctx| Self::from_ref(&*(ctx as *const Context<T>), pin, value))
+    pub unsafe fn new<K: ContextKey<T, N>, const N: usize>(
+        context: &'static K,
+        pin: &'a StackPin,
+    ) -> Result<Self, ContextError> {
+        context.try_with_context(move |ctx| Self::from_ref(ctx, pin))?
     }
+}
 
-    pub fn guard(&self) -> StackGuard<'_, T> {
-        StackGuard {
-            value: self.value,
+impl<'a, T, const N: usize> Iter<'a, T, N> {
+    /// # Safety
+    ///
+    /// The `StackPin` must not outlive the current stack frame, and no value may be
+    /// popped from `context` while the returned iterator or any value it yields is alive
+    ///
+    /// # Panics
+    ///
+    /// If any value in the context is mutably borrowed
+    pub unsafe fn from_ref(ctx: &Context<T, N>, _: &'a StackPin) -> Self {
+        ctx.borrow.borrow_all();
+        Self {
+            ctx: Some(&*(ctx as *const Context<T, N>)),
+            front: ctx.base.get(),
+            back: ctx.len.get(),
+            borrow: Some(&*(&ctx.borrow as *const BorrowState)),
             stack_pin: PhantomData,
         }
     }
+
+    /// An empty iterator, if `context` has been destroyed
+    #[doc(hidden)]
+    pub unsafe fn new<K: ContextKey<T, N>>(context: &'static K, pin: &'a StackPin) -> Self {
+        context.try_with_context(move |ctx| Self::from_ref(ctx, pin)).unwrap_or(Self {
+            ctx: None,
+            front: 0,
+            back: 0,
+            borrow: None,
+            stack_pin: PhantomData,
+        })
+    }
 }
 
-impl<T> core::ops::Deref for StackGuard<'_, T> {
-    type Target = T;
+impl<'a, T, const N: usize> Iterator for Iter<'a, T, N> {
+    type Item = &'a T;
 
-    fn deref(&self) -> &Self::Target { unsafe { self.value.as_ref() } }
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None
+        }
+
+        self.back -= 1;
+        unsafe { Some(&*self.ctx?.slot(self.back)) }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.back - self.front;
+        (len, Some(len))
+    }
 }
 
-impl<T> core::ops::Deref for Item<'_, '_, T> {
-    type Target = T;
+impl<T, const N: usize> DoubleEndedIterator for Iter<'_, T, N> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None
+        }
 
-    fn deref(&self) -> &Self::Target { unsafe { self.value.as_ref() } }
+        let index = self.front;
+        self.front += 1;
+        unsafe { Some(&*self.ctx?.slot(index)) }
+    }
 }
 
-impl<T> Drop for Item<'_, '_, T> {
+impl<T, const N: usize> Drop for Iter<'_, T, N> {
     fn drop(&mut self) {
-        unsafe {
-            #[cfg(miri)]
-            self.value
-                .as_ptr()
-                .cast::<MaybeUninit<T>>()
-                .write(MaybeUninit::uninit());
-            self.ctx.pop();
+        if let Some(borrow) = self.borrow {
+            borrow.release();
         }
     }
 }
 
-impl<T> Context<T> {
-    pub fn new(block_capacity: usize) -> Self {
-        Self {
-            blocks: Default::default(),
-            block_capacity: NonZeroUsize::new(block_capacity).expect("The block capacity must be non-zero"),
-            len: Cell::new(0),
-        }
+impl<T, const N: usize> ExactSizeIterator for Iter<'_, T, N> {}
+
+impl<T, const N: usize> std::iter::FusedIterator for Iter<'_, T, N> {}
+
+impl<'ctx, 'a, T, const N: usize> Item<'ctx, 'a, T, N> {
+    /// # Safety
+    ///
+    /// The `StackPin` must not outlive the current stack frame, and items pushed onto
+    /// `ctx` must be dropped in the reverse order that they were created
+    pub unsafe fn from_ref(ctx: &'ctx Context<T, N>, _: &'a StackPin, value: T) -> Self {
+        Self::pushed(ctx, ctx.push(value))
     }
 
-    pub fn len(&self) -> usize {
-        let blocks = unsafe { &*self.blocks.get() };
-        blocks.len()
+    /// Like [`from_ref`](Self::from_ref), but returns the value if it couldn't be pushed, see [`Context::try_push`]
+    ///
+    /// # Safety
+    ///
+    /// See [`from_ref`](Self::from_ref)
+    pub unsafe fn try_from_ref(ctx: &'ctx Context<T, N>, _: &'a StackPin, value: T) -> Result<Self, (T, PushError)> {
+        ctx.try_push(value).map(|value| Self::pushed(ctx, value))
     }
 
-    #[cold]
-    #[inline(never)]
-    fn reserve_block(&self) {
-        let block_capacity = self.block_capacity.get();
-        let mut block = Vec::<MaybeUninit<T>>::with_capacity(block_capacity);
-        unsafe {
-            block.set_len(block_capacity);
+    fn pushed(ctx: &'ctx Context<T, N>, value: NonNull<T>) -> Self {
+        Self {
+            value,
+            ctx,
+            // the item itself hands out shared references to its value
+            borrow: ctx.borrow.enter(1),
+            detached: None,
+            stack_pin: PhantomData,
         }
-        let blocks = unsafe { &mut *self.blocks.get() };
-        blocks.push(Box::into_raw(block.into_boxed_slice()).cast::<T>());
     }
 
-    pub fn push(&self, value: T) -> NonNull<T> {
-        let block_capacity = self.block_capacity.get();
-        let len = self.len.get();
-        let block = len / block_capacity;
-        let slot = len % block_capacity;
+    /// If `context` has been destroyed, the value is pushed onto a new context
+    /// instead, so it's still owned by the item but `get!` can't see it
+    #[doc(hidden)]
+    pub unsafe fn new<K: ContextKey<T, N>>(context: &'static K, pin: &'a StackPin, value: T) -> Self {
+        with_context_or(
+            context,
+            value,
+            |ctx, value| Self::from_ref(&*(ctx as *const Context<T, N>), pin, value),
+            |value| Self::detached(value, |ctx, value| Ok(Self::from_ref(ctx, pin, value))).unwrap_or_else(|_| unreachable!()),
+        )
+    }
 
-        if slot == 0 && block >= self.len() {
-            self.reserve_block();
-        }
+    #[doc(hidden)]
+    pub unsafe fn try_new<K: ContextKey<T, N>>(
+        context: &'static K,
+        pin: &'a StackPin,
+        value: T,
+    ) -> Result<Self, (T, PushError)> {
+        with_context_or(
+            context,
+            value,
+            |ctx, value| Self::try_from_ref(&*(ctx as *const Context<T, N>), pin, value),
+            |value| Self::detached(value, |ctx, value| Self::try_from_ref(ctx, pin, value)),
+        )
+    }
 
-        unsafe {
-            self.len.set(len + 1);
-            let blocks = &*self.blocks.get();
-            let slot = blocks.get_unchecked(block).add(slot);
-            slot.write(value);
-            NonNull::new_unchecked(slot)
+    unsafe fn detached(
+        value: T,
+        push: impl FnOnce(&'ctx Context<T, N>, T) -> Result<Self, (T, PushError)>,
+    ) -> Result<Self, (T, PushError)> {
+        let ctx = Box::into_raw(Box::new(Context::from_parts(NonZeroUsize::new(1).unwrap(), 0)));
+        match push(&*ctx, value) {
+            Ok(mut item) => {
+                item.detached = NonNull::new(ctx);
+                Ok(item)
+            }
+            Err(error) => {
+                drop(Box::from_raw(ctx));
+                Err(error)
+            }
         }
     }
 
-    pub fn top(&self) -> Option<NonNull<T>> {
-        let block_capacity = self.block_capacity.get();
-        let len = self.len.get().checked_sub(1)?;
-        let block = len / block_capacity;
-        let slot = len % block_capacity;
-
-        unsafe {
-            let blocks = &*self.blocks.get();
-            let slot = blocks.get_unchecked(block).add(slot);
-            Some(NonNull::new_unchecked(slot))
+    pub fn guard(&self) -> StackGuard<'_, T> {
+        self.ctx.borrow.borrow();
+        StackGuard {
+            value: self.value,
+            borrow: &self.ctx.borrow,
+            stack_pin: PhantomData,
         }
     }
 
-    pub unsafe fn pop(&self) { self.len.set(self.len.get().wrapping_sub(1)); }
+    /// Mutably borrows the value pushed by this item
+    ///
+    /// # Panics
+    ///
+    /// If the value is not on the top of the context, or it is borrowed by a guard
+    pub fn get_mut(&mut self) -> StackGuardMut<'_, T> {
+        assert!(
+            self.ctx.top() == Some(self.value),
+            "Tried to mutably borrow a value which is not on the top of the context"
+        );
+        self.ctx.borrow.borrow_mut(1);
+        StackGuardMut {
+            value: self.value,
+            borrow: &self.ctx.borrow,
+            shared: 1,
+            stack_pin: PhantomData,
+        }
+    }
 }
 
-#[test]
-fn push_pop() {
-    thread_local! {
-        static CONTEXT: Context<i32> = Context::new(16);
-    }
+impl<T> core::ops::Deref for StackGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target { unsafe { self.value.as_ref() } }
+}
+
+impl<T> Drop for StackGuard<'_, T> {
+    fn drop(&mut self) { self.borrow.release() }
+}
+
+impl<T> core::ops::Deref for StackGuardMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target { unsafe { self.value.as_ref() } }
+}
+
+impl<T> core::ops::DerefMut for StackGuardMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target { unsafe { self.value.as_mut() } }
+}
+
+impl<T> Drop for StackGuardMut<'_, T> {
+    fn drop(&mut self) { self.borrow.release_mut(self.shared) }
+}
+
+impl<T, const N: usize> core::ops::Deref for Item<'_, '_, T, N> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target { unsafe { self.value.as_ref() } }
+}
+
+impl<T, const N: usize> ReplaceTop<'_, T, N> {
+    /// The value that will be restored once this guard is dropped
+    pub fn original(&self) -> &T { self.value.as_ref().unwrap() }
+}
+
+impl<T, const N: usize> Drop for ReplaceTop<'_, T, N> {
+    fn drop(&mut self) {
+        assert!(
+            self.ctx.len.get() == self.len,
+            "Tried to restore a context value which is no longer on the top of the context"
+        );
+        self.ctx.borrow.assert_unborrowed();
+
+        if let (Some(top), Some(value)) = (self.ctx.top(), self.value.take()) {
+            drop(unsafe { top.as_ptr().replace(value) })
+        }
+    }
+}
+
+impl<T, const N: usize> Drop for Item<'_, '_, T, N> {
+    fn drop(&mut self) {
+        // a value pushed with `LocalContext::push` may have escaped from the item's scope
+        let on_top = self.ctx.top() == Some(self.value);
+        self.ctx.borrow.exit(self.borrow);
+        assert!(
+            on_top || std::thread::panicking(),
+            "Tried to pop a context value which is no longer on the top of the context"
+        );
+
+        if on_top {
+            unsafe { self.ctx.pop() }
+        }
+
+        if let Some(ctx) = self.detached {
+            drop(unsafe { Box::from_raw(ctx.as_ptr()) })
+        }
+    }
+}
+
+struct BlockCapacity<const N: usize>;
+
+impl<const N: usize> BlockCapacity<N> {
+    const VALUE: NonZeroUsize = match NonZeroUsize::new(N) {
+        Some(block_capacity) => block_capacity,
+        None => panic!("The block capacity must be non-zero"),
+    };
+}
+
+impl<T> SyncContext<T> {
+    pub const fn new(block_capacity: usize) -> Self { Self::new_inline(block_capacity) }
+}
+
+impl<T, const N: usize> SyncContext<T, N> {
+    /// Creates a context which stores the first `N` values of every thread's stack inline
+    pub const fn new_inline(block_capacity: usize) -> Self {
+        match NonZeroUsize::new(block_capacity) {
+            Some(block_capacity) => Self {
+                block_capacity,
+                value: PhantomData,
+            },
+            None => panic!("The block capacity must be non-zero"),
+        }
+    }
+}
+
+impl<T> Context<T> {
+    pub fn new(block_capacity: usize) -> Self { Self::with_max_spare_blocks(block_capacity, usize::MAX) }
+
+    /// Creates a context in a const context, so it can be used in a `thread_local!`
+    /// with `const { ... }` initialization
+    ///
+    /// A block capacity of zero is rejected at compile time
+    ///
+    /// ```
+    /// # use contextual::Context;
+    /// thread_local! {
+    ///     static CONTEXT: Context<u32> = const { Context::new_const::<16>() };
+    /// }
+    /// ```
+    ///
+    /// ```compile_fail
+    /// # use contextual::Context;
+    /// let _ = Context::<u32>::new_const::<0>();
+    /// ```
+    pub const fn new_const<const BLOCK_CAPACITY: usize>() -> Self {
+        Self::from_parts(BlockCapacity::<BLOCK_CAPACITY>::VALUE, usize::MAX)
+    }
+
+    /// Creates a context which will deallocate empty blocks whenever more than
+    /// `max_spare_blocks` are unused
+    pub fn with_max_spare_blocks(block_capacity: usize, max_spare_blocks: usize) -> Self {
+        Self::from_parts(
+            NonZeroUsize::new(block_capacity).expect("The block capacity must be non-zero"),
+            max_spare_blocks,
+        )
+    }
+}
+
+impl<T, const N: usize> Context<T, N> {
+    const fn from_parts(block_capacity: NonZeroUsize, max_spare_blocks: usize) -> Self {
+        Self {
+            inline: UnsafeCell::new(MaybeUninit::uninit()),
+            blocks: UnsafeCell::new(Vec::new()),
+            block_capacity,
+            max_spare_blocks,
+            len: Cell::new(0),
+            base: Cell::new(0),
+            default: None,
+            default_hidden: Cell::new(false),
+            max_depth_seen: Cell::new(0),
+            max_depth: usize::MAX,
+            overflow_policy: OverflowPolicy::Panic,
+            borrow: BorrowState::new(),
+        }
+    }
+
+    /// Creates a context which stores the first `N` values inline
+    pub fn new_inline(block_capacity: usize) -> Self {
+        Self::from_parts(
+            NonZeroUsize::new(block_capacity).expect("The block capacity must be non-zero"),
+            usize::MAX,
+        )
+    }
+
+    /// Creates a context which stores the first `N` values inline in a const context,
+    /// see [`Context::new_const`]
+    ///
+    /// ```
+    /// # use contextual::InlineContext;
+    /// thread_local! {
+    ///     static CONTEXT: InlineContext<u32, 4> = const { InlineContext::new_inline_const::<16>() };
+    /// }
+    /// ```
+    pub const fn new_inline_const<const BLOCK_CAPACITY: usize>() -> Self {
+        Self::from_parts(BlockCapacity::<BLOCK_CAPACITY>::VALUE, usize::MAX)
+    }
+
+    /// The number of values currently pushed onto the context
+    pub fn len(&self) -> usize { self.len.get() - self.base.get() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// The number of values on the stack, including values hidden by [`Context::restore`]
+    pub fn depth(&self) -> usize { self.len.get() }
+
+    /// The largest [`depth`](Self::depth) this context has ever had
+    pub fn max_depth_seen(&self) -> usize { self.max_depth_seen.get() }
+
+    /// How full each block is, starting with the inline storage, in the order the blocks are filled
+    ///
+    /// Values hidden by [`Context::restore`] are included
+    pub fn occupancy(&self) -> impl Iterator<Item = Occupancy> {
+        let inline_len = self.len.get().min(N);
+        let mut len = self.len.get() - inline_len;
+        let block_capacity = self.block_capacity.get();
+
+        let inline = Some(Occupancy {
+            len: inline_len,
+            capacity: N,
+            inline: true,
+        });
+
+        let blocks = (0..self.allocated_blocks()).map(move |_| {
+            let block_len = len.min(block_capacity);
+            len -= block_len;
+            Occupancy {
+                len: block_len,
+                capacity: block_capacity,
+                inline: false,
+            }
+        });
+
+        inline.filter(|_| N != 0).into_iter().chain(blocks)
+    }
+
+    /// The number of blocks that have been allocated
+    pub fn allocated_blocks(&self) -> usize {
+        let blocks = unsafe { &*self.blocks.get() };
+        blocks.len()
+    }
+
+    /// The number of values that can be pushed without allocating another block
+    pub fn capacity(&self) -> usize { N + self.allocated_blocks() * self.block_capacity.get() }
+
+    /// The number of bytes allocated on the heap by this context
+    pub fn memory_usage(&self) -> usize {
+        let blocks = unsafe { &*self.blocks.get() };
+        let block_size = self.block_capacity.get() * core::mem::size_of::<T>();
+        blocks.len() * block_size + blocks.capacity() * core::mem::size_of::<*mut T>()
+    }
+
+    /// Deallocates all blocks that don't contain any values
+    pub fn shrink_to_fit(&self) {
+        self.release_blocks(0);
+        let blocks = unsafe { &mut *self.blocks.get() };
+        blocks.shrink_to_fit();
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn release_blocks(&self, max_spare_blocks: usize) {
+        let block_capacity = self.block_capacity.get();
+        let used_blocks = self.len.get().saturating_sub(N).div_ceil(block_capacity);
+        let blocks = unsafe { &mut *self.blocks.get() };
+
+        while blocks.len() > used_blocks + max_spare_blocks {
+            if let Some(block) = blocks.pop() {
+                unsafe {
+                    let block = core::ptr::slice_from_raw_parts_mut(block.cast::<MaybeUninit<T>>(), block_capacity);
+                    drop(Box::from_raw(block));
+                }
+            }
+        }
+    }
+
+    unsafe fn slot(&self, index: usize) -> *mut T {
+        let index = match index.checked_sub(N) {
+            Some(index) => index,
+            None => return self.inline.get().cast::<T>().add(index),
+        };
+
+        let block_capacity = self.block_capacity.get();
+        let block = index / block_capacity;
+        let slot = index % block_capacity;
+
+        let blocks = &*self.blocks.get();
+        blocks.get_unchecked(block).add(slot)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn reserve_block(&self) {
+        let block_capacity = self.block_capacity.get();
+        let mut block = Vec::<MaybeUninit<T>>::with_capacity(block_capacity);
+        unsafe {
+            block.set_len(block_capacity);
+        }
+        let blocks = unsafe { &mut *self.blocks.get() };
+        blocks.push(Box::into_raw(block.into_boxed_slice()).cast::<T>());
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn try_reserve_block(&self) -> Result<(), AllocError> {
+        let block_capacity = self.block_capacity.get();
+        let blocks = unsafe { &mut *self.blocks.get() };
+        blocks.try_reserve(1).map_err(|_| AllocError)?;
+
+        let mut block = Vec::<MaybeUninit<T>>::new();
+        block.try_reserve_exact(block_capacity).map_err(|_| AllocError)?;
+        unsafe {
+            block.set_len(block_capacity);
+        }
+        blocks.push(Box::into_raw(block.into_boxed_slice()).cast::<T>());
+        Ok(())
+    }
+
+    /// Returns true if pushing a value at `len` needs another block
+    fn needs_block(&self, len: usize) -> bool {
+        match len.checked_sub(N) {
+            Some(index) => index % self.block_capacity.get() == 0 && index / self.block_capacity.get() >= self.allocated_blocks(),
+            None => false,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `len` must be the length of the context, and the slot at `len` must be allocated
+    unsafe fn write(&self, len: usize, value: T) -> NonNull<T> {
+        let slot = self.slot(len);
+        slot.write(value);
+        self.len.set(len + 1);
+        self.max_depth_seen.set(self.max_depth_seen.get().max(len + 1));
+        NonNull::new_unchecked(slot)
+    }
+
+    /// # Panics
+    ///
+    /// If the context is at its max depth, and the overflow policy is [`OverflowPolicy::Panic`]
+    pub fn push(&self, value: T) -> NonNull<T> {
+        let len = self.len.get();
+
+        if len >= self.max_depth {
+            self.overflow()
+        }
+
+        if self.needs_block(len) {
+            self.reserve_block();
+        }
+
+        unsafe { self.write(len, value) }
+    }
+
+    /// Pushes a value onto the context, or returns it if the context is at its max
+    /// depth or a new block couldn't be allocated
+    pub fn try_push(&self, value: T) -> Result<NonNull<T>, (T, PushError)> {
+        let len = self.len.get();
+
+        if len >= self.max_depth {
+            return Err((value, PushError::DepthExceeded { max_depth: self.max_depth }))
+        }
+
+        if self.needs_block(len) {
+            if let Err(error) = self.try_reserve_block() {
+                return Err((value, error.into()))
+            }
+        }
+
+        Ok(unsafe { self.write(len, value) })
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn overflow(&self) -> ! {
+        let error = PushError::DepthExceeded {
+            max_depth: self.max_depth,
+        };
+
+        match self.overflow_policy {
+            OverflowPolicy::Panic => panic!("{}", error),
+            OverflowPolicy::Abort => {
+                eprintln!("{}", error);
+                std::process::abort()
+            }
+        }
+    }
+
+    /// Limits the number of values on the context, including values hidden by [`Context::restore`]
+    ///
+    /// ```
+    /// # use contextual::{Context, OverflowPolicy, PushError};
+    /// let ctx = Context::new(16).with_max_depth(1, OverflowPolicy::Panic);
+    ///
+    /// ctx.push(0);
+    /// assert_eq!(ctx.try_push(1).unwrap_err(), (1, PushError::DepthExceeded { max_depth: 1 }));
+    /// ```
+    pub fn with_max_depth(mut self, max_depth: usize, policy: OverflowPolicy) -> Self {
+        self.max_depth = max_depth;
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// The max depth set by [`with_max_depth`](Self::with_max_depth)
+    pub fn max_depth(&self) -> Option<usize> { Some(self.max_depth).filter(|&max_depth| max_depth != usize::MAX) }
+
+    /// Sets the value which is used as the top of the context when it's empty
+    ///
+    /// ```
+    /// # use contextual::{get, push, Context};
+    /// thread_local! {
+    ///     static LOCALE: Context<&'static str> = Context::new(16).with_default("en");
+    /// }
+    ///
+    /// {
+    ///     push!(let _locale: LOCALE = "fr");
+    ///     get!(let locale: LOCALE);
+    ///     assert_eq!(*locale, "fr");
+    /// }
+    ///
+    /// get!(let locale: LOCALE);
+    /// assert_eq!(*locale, "en");
+    /// ```
+    pub fn with_default(mut self, value: T) -> Self {
+        self.default = Some(UnsafeCell::new(value));
+        self
+    }
+
+    pub fn has_default(&self) -> bool { self.default.is_some() }
+
+    /// An empty context with the same configuration and default value
+    #[cfg(feature = "tokio")]
+    fn new_like(&self) -> Self
+    where
+        T: Clone,
+    {
+        assert!(self.borrow.mut_borrows.get() == 0, "Tried to get from a mutably borrowed context");
+
+        let mut ctx = Self::from_parts(self.block_capacity, self.max_spare_blocks);
+        ctx.default = self.default.as_ref().map(|default| UnsafeCell::new(unsafe { &*default.get() }.clone()));
+        ctx.max_depth = self.max_depth;
+        ctx.overflow_policy = self.overflow_policy;
+        ctx
+    }
+
+    /// The top value of the context, or the default value if the context is empty
+    ///
+    /// The default value is hidden while [`Context::restore`] is running
+    pub fn top(&self) -> Option<NonNull<T>> {
+        let len = self.len.get();
+        if len == self.base.get() {
+            return self
+                .default
+                .as_ref()
+                .filter(|_| !self.default_hidden.get())
+                .map(|default| unsafe { NonNull::new_unchecked(default.get()) })
+        }
+
+        let len = len - 1;
+        unsafe { Some(NonNull::new_unchecked(self.slot(len))) }
+    }
+
+    /// Iterates over all values in the context, from the top of the stack to the bottom
+    ///
+    /// Values pushed after the iterator is created will not be yielded
+    ///
+    /// # Safety
+    ///
+    /// No value may be popped from the context while the returned iterator
+    /// or any value it yields is alive
+    pub unsafe fn iter(&self) -> Iter<'_, T, N> {
+        Iter {
+            ctx: Some(self),
+            front: self.base.get(),
+            back: self.len.get(),
+            borrow: None,
+            stack_pin: PhantomData,
+        }
+    }
+
+    /// Iterates over all values in the context, from the bottom of the stack to the top
+    ///
+    /// # Safety
+    ///
+    /// See [`Context::iter`]
+    pub unsafe fn iter_rev(&self) -> std::iter::Rev<Iter<'_, T, N>> { self.iter().rev() }
+
+    /// Pushes `value` onto the context for the duration of `f`
+    ///
+    /// The value is popped once `f` returns, or if `f` panics
+    pub fn scope<R>(&self, value: T, f: impl FnOnce(&T) -> R) -> R {
+        let stack_pin = unsafe { StackPin::new() };
+        let item = unsafe { Item::from_ref(self, &stack_pin, value) };
+        f(&item)
+    }
+
+    /// Pushes a value derived from the top value onto the context for the duration of `f`
+    ///
+    /// # Panics
+    ///
+    /// If the context is empty or the top value is mutably borrowed
+    pub fn with_modified<R>(&self, modify: impl FnOnce(&T) -> T, f: impl FnOnce(&T) -> R) -> R {
+        let stack_pin = unsafe { StackPin::new() };
+        let top = unsafe { StackGuard::from_ref(self, &stack_pin) };
+        let value = modify(&top.expect("Tried to get from an empty context"));
+        self.scope(value, f)
+    }
+
+    /// Replaces the top value in place without pushing a new value,
+    /// the original value is restored once the returned guard is dropped
+    ///
+    /// The top value must not be borrowed, so this can't replace values pushed
+    /// with [`push!`] or [`Context::scope`] while they are in scope, see [`Context::lend`]
+    ///
+    /// # Panics
+    ///
+    /// If the context is empty or the top value is borrowed, and on drop if the
+    /// guard is not dropped before the values pushed after it
+    pub fn replace_top(&self, value: T) -> ReplaceTop<'_, T, N> {
+        let top = self.top().expect("Tried to get from an empty context");
+        self.borrow.assert_unborrowed();
+
+        ReplaceTop {
+            ctx: self,
+            len: self.len.get(),
+            value: Some(unsafe { top.as_ptr().replace(value) }),
+        }
+    }
+
+    /// Moves the value out of `value` and onto the context for the duration of `f`,
+    /// then moves it back into `value`
+    ///
+    /// The value is moved back even if `f` panics
+    ///
+    /// Unlike values pushed with [`push!`] or [`Context::scope`], the lent value
+    /// can be mutably borrowed with [`get_mut!`] while `f` runs
+    ///
+    /// # Panics
+    ///
+    /// If `value` is `None`, or if a value pushed while `f` ran is still on the context when it returns
+    pub fn lend<R>(&self, value: &mut Option<T>, f: impl FnOnce() -> R) -> R {
+        struct Restore<'a, T, const N: usize> {
+            ctx: &'a Context<T, N>,
+            value: &'a mut Option<T>,
+            len: usize,
+            borrow: isize,
+        }
+
+        impl<T, const N: usize> Drop for Restore<'_, T, N> {
+            fn drop(&mut self) {
+                let on_top = self.ctx.len.get() == self.len;
+                self.ctx.borrow.exit(self.borrow);
+                assert!(
+                    on_top || std::thread::panicking(),
+                    "Tried to take back a lent value which is no longer on the top of the context"
+                );
+
+                if on_top {
+                    *self.value = Some(unsafe { self.ctx.take() });
+                }
+            }
+        }
+
+        self.push(value.take().expect("Tried to lend an empty value"));
+        // nothing outside of `f` can reference the lent value, so it starts out unborrowed
+        let borrow = self.borrow.enter(0);
+        let _restore = Restore {
+            ctx: self,
+            value,
+            len: self.len.get(),
+            borrow,
+        };
+        f()
+    }
+
+    /// Pushes all `values` onto the context for the duration of `f`
+    fn scope_all<R>(&self, values: impl IntoIterator<Item = T>, f: impl FnOnce() -> R) -> R {
+        struct Restore<'a, T, const N: usize> {
+            ctx: &'a Context<T, N>,
+            len: usize,
+            borrow: isize,
+        }
+
+        impl<T, const N: usize> Drop for Restore<'_, T, N> {
+            fn drop(&mut self) {
+                self.ctx.borrow.exit(self.borrow);
+                unsafe { self.ctx.truncate(self.len) }
+            }
+        }
+
+        let restore = Restore {
+            ctx: self,
+            len: self.len.get(),
+            borrow: self.borrow.top.get(),
+        };
+
+        for value in values {
+            self.push(value);
+        }
+
+        // nothing outside of `f` can reference the pushed values, so they start out unborrowed
+        if self.len.get() > restore.len {
+            self.borrow.enter(0);
+        }
+
+        f()
+    }
+
+    /// Moves all `values` onto the context for the duration of `f`, then moves them back into `values`
+    fn lend_all<R>(&self, values: &mut Vec<T>, f: impl FnOnce() -> R) -> R {
+        struct Restore<'a, T, const N: usize> {
+            ctx: &'a Context<T, N>,
+            values: &'a mut Vec<T>,
+            len: usize,
+            borrow: isize,
+        }
+
+        impl<T, const N: usize> Drop for Restore<'_, T, N> {
+            fn drop(&mut self) {
+                self.ctx.borrow.exit(self.borrow);
+
+                let start = self.values.len();
+                while self.ctx.len.get() > self.len {
+                    self.values.push(unsafe { self.ctx.take() });
+                }
+                self.values[start..].reverse();
+            }
+        }
+
+        let restore = Restore {
+            ctx: self,
+            len: self.len.get(),
+            borrow: self.borrow.top.get(),
+            values,
+        };
+
+        for value in restore.values.drain(..) {
+            self.push(value);
+        }
+
+        // nothing outside of `f` can reference the lent values, so they start out unborrowed
+        if self.len.get() > restore.len {
+            self.borrow.enter(0);
+        }
+
+        f()
+    }
+
+    /// Pops values until there are only `len` values left
+    ///
+    /// # Safety
+    ///
+    /// There must not be any live references to the popped values
+    unsafe fn truncate(&self, len: usize) {
+        struct Truncate<'a, T, const N: usize>(&'a Context<T, N>, usize);
+
+        impl<T, const N: usize> Drop for Truncate<'_, T, N> {
+            fn drop(&mut self) {
+                while self.0.len.get() > self.1 {
+                    unsafe { self.0.pop() }
+                }
+            }
+        }
+
+        let on_panic = Truncate(self, len);
+
+        while self.len.get() > len {
+            self.pop()
+        }
+
+        core::mem::forget(on_panic);
+    }
+
+    /// Removes the top value from the context and returns it
+    ///
+    /// # Safety
+    ///
+    /// The context must not be empty, and there must not be any live references
+    /// to the top value
+    pub unsafe fn take(&self) -> T {
+        let len = self.len.get() - 1;
+        self.len.set(len);
+        let slot = self.slot(len);
+        let value = slot.read();
+        #[cfg(miri)]
+        slot.cast::<MaybeUninit<T>>().write(MaybeUninit::uninit());
+
+        if let Some(index) = len.checked_sub(N) {
+            let block_capacity = self.block_capacity.get();
+            if index.is_multiple_of(block_capacity) && self.allocated_blocks() - index / block_capacity > self.max_spare_blocks {
+                self.release_blocks(self.max_spare_blocks);
+            }
+        }
+
+        value
+    }
+
+    /// Removes the top value from the context and drops it
+    ///
+    /// The value is removed before it's destructor runs, so even if it
+    /// panics it will not be dropped again
+    ///
+    /// # Safety
+    ///
+    /// The context must not be empty, and there must not be any live references
+    /// to the top value
+    pub unsafe fn pop(&self) { drop(self.take()) }
+}
+
+#[test]
+fn push_pop() {
+    thread_local! {
+        static CONTEXT: Context<i32> = Context::new(16);
+    }
 
     push!(let item: CONTEXT = 10);
     get!(let guard: CONTEXT);
@@ -273,3 +1631,1001 @@ fn push_lots() {
         ctx.push(Box::new(10));
     }
 }
+
+#[cfg(test)]
+struct DropCounter<'a>(&'a Cell<usize>);
+
+#[cfg(test)]
+impl Drop for DropCounter<'_> {
+    fn drop(&mut self) { self.0.set(self.0.get() + 1); }
+}
+
+#[test]
+fn pop_drops() {
+    let drops = Cell::new(0);
+    let ctx = Context::new(4);
+
+    for _ in 0..10 {
+        ctx.push(DropCounter(&drops));
+    }
+
+    for i in 1..=10 {
+        unsafe { ctx.pop() }
+        assert_eq!(drops.get(), i);
+    }
+
+    drop(ctx);
+    assert_eq!(drops.get(), 10);
+}
+
+#[test]
+fn drop_remaining() {
+    let drops = Cell::new(0);
+    let ctx = Context::new(4);
+
+    for _ in 0..10 {
+        ctx.push(DropCounter(&drops));
+    }
+
+    unsafe { ctx.pop() }
+    assert_eq!(drops.get(), 1);
+    drop(ctx);
+    assert_eq!(drops.get(), 10);
+}
+
+#[test]
+fn item_drops() {
+    thread_local! {
+        static CONTEXT: Context<std::rc::Rc<()>> = Context::new(2);
+    }
+
+    let value = std::rc::Rc::new(());
+
+    {
+        push!(let _a: CONTEXT = value.clone());
+        push!(let _b: CONTEXT = value.clone());
+        push!(let _c: CONTEXT = value.clone());
+        assert_eq!(std::rc::Rc::strong_count(&value), 4);
+    }
+
+    assert_eq!(std::rc::Rc::strong_count(&value), 1);
+}
+
+#[test]
+fn pop_panic_drops_once() {
+    struct PanicOnDrop<'a> {
+        _counter: DropCounter<'a>,
+        panic: bool,
+    }
+
+    impl Drop for PanicOnDrop<'_> {
+        fn drop(&mut self) {
+            if self.panic {
+                panic!("drop")
+            }
+        }
+    }
+
+    let drops = Cell::new(0);
+    let ctx = Context::new(4);
+
+    ctx.push(PanicOnDrop {
+        _counter: DropCounter(&drops),
+        panic: false,
+    });
+    ctx.push(PanicOnDrop {
+        _counter: DropCounter(&drops),
+        panic: true,
+    });
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe { ctx.pop() }));
+    assert!(result.is_err());
+    assert_eq!(drops.get(), 1);
+
+    drop(ctx);
+    assert_eq!(drops.get(), 2);
+}
+
+#[test]
+fn scope() {
+    thread_local! {
+        static CONTEXT: Context<i32> = Context::new(2);
+    }
+
+    let value = CONTEXT.scope(10, |&a| {
+        CONTEXT.scope(20, |&b| {
+            get!(let top: CONTEXT);
+            assert_eq!(*top, 20);
+            a + b
+        })
+    });
+
+    assert_eq!(value, 30);
+    try_get!(let top: CONTEXT);
+    assert_eq!(top, Err(ContextError::Empty));
+}
+
+#[test]
+fn scope_panic() {
+    let drops = Cell::new(0);
+    let ctx = Context::new(2);
+
+    ctx.scope(DropCounter(&drops), |_| {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            ctx.scope(DropCounter(&drops), |_| ctx.scope(DropCounter(&drops), |_| panic!()))
+        }));
+        assert!(result.is_err());
+        assert_eq!(drops.get(), 2);
+    });
+
+    assert_eq!(drops.get(), 3);
+    assert!(ctx.top().is_none());
+}
+
+#[cfg(test)]
+fn block_on_threads<F: Future + Send + 'static>(future: F) -> F::Output
+where
+    F::Output: Send,
+{
+    struct NoopWaker;
+
+    impl std::task::Wake for NoopWaker {
+        fn wake(self: std::sync::Arc<Self>) {}
+    }
+
+    let waker = std::task::Waker::from(std::sync::Arc::new(NoopWaker));
+    let mut future = Box::pin(future);
+
+    // poll every time on a new thread, to emulate a work-stealing executor
+    loop {
+        let waker = waker.clone();
+        let (fut, poll) = std::thread::spawn(move || {
+            let poll = future.as_mut().poll(&mut task::Context::from_waker(&waker));
+            (future, poll)
+        })
+        .join()
+        .unwrap();
+
+        match poll {
+            Poll::Ready(value) => return value,
+            Poll::Pending => future = fut,
+        }
+    }
+}
+
+#[cfg(test)]
+struct YieldNow(bool);
+
+#[cfg(test)]
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<()> {
+        if std::mem::replace(&mut self.0, true) {
+            Poll::Ready(())
+        } else {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[test]
+fn scope_future() {
+    thread_local! {
+        static CONTEXT: Context<String> = Context::new(2);
+    }
+
+    let future = CONTEXT.scope_future("outer".to_string(), async {
+        for _ in 0..3 {
+            {
+                get!(let value: CONTEXT);
+                assert_eq!(value, "outer");
+            }
+            YieldNow(false).await;
+        }
+
+        CONTEXT
+            .scope_future("inner".to_string(), async {
+                YieldNow(false).await;
+                get!(let value: CONTEXT);
+                value.clone()
+            })
+            .await
+    });
+
+    assert_eq!(block_on_threads(future), "inner");
+    try_get!(let value: CONTEXT);
+    assert_eq!(value, Err(ContextError::Empty));
+}
+
+#[test]
+fn lend() {
+    let ctx = Context::new(2);
+    let mut value = Some(10);
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        ctx.lend(&mut value, || {
+            assert_eq!(unsafe { *ctx.top().unwrap().as_ref() }, 10);
+            panic!()
+        })
+    }));
+
+    assert!(result.is_err());
+    assert_eq!(value, Some(10));
+    assert!(ctx.top().is_none());
+}
+
+#[test]
+fn iter() {
+    let ctx = Context::new(3);
+
+    for i in 0..10 {
+        ctx.push(i);
+    }
+
+    unsafe {
+        assert!(ctx.iter().copied().eq((0..10).rev()));
+        assert!(ctx.iter_rev().copied().eq(0..10));
+        assert_eq!(ctx.iter().len(), 10);
+
+        let mut iter = ctx.iter();
+        assert_eq!(iter.next(), Some(&9));
+        assert_eq!(iter.next_back(), Some(&0));
+        assert_eq!(iter.len(), 8);
+
+        ctx.pop();
+        assert!(ctx.iter().copied().eq((0..9).rev()));
+    }
+}
+
+#[test]
+fn get_all() {
+    thread_local! {
+        static CONTEXT: Context<&'static str> = Context::new(2);
+    }
+
+    fn all_spans() -> Vec<&'static str> {
+        get_all!(let spans: CONTEXT);
+        spans.copied().collect()
+    }
+
+    assert!(all_spans().is_empty());
+
+    push!(let _a: CONTEXT = "a");
+    push!(let _b: CONTEXT = "b");
+
+    CONTEXT.scope("c", |_| {
+        get_all!(let spans: CONTEXT);
+        CONTEXT.scope("d", |_| assert_eq!(all_spans(), ["d", "c", "b", "a"]));
+        assert!(spans.copied().eq(["c", "b", "a"].iter().copied()));
+    });
+
+    assert_eq!(all_spans(), ["b", "a"]);
+}
+
+#[test]
+fn len() {
+    let ctx = Context::new(4);
+    assert!(ctx.is_empty());
+    assert_eq!(ctx.capacity(), 0);
+
+    for i in 1..=9 {
+        ctx.push(i);
+        assert_eq!(ctx.len(), i);
+        assert_eq!(ctx.allocated_blocks(), i.div_ceil(4));
+    }
+
+    assert_eq!(ctx.capacity(), 12);
+
+    for i in (0..9).rev() {
+        unsafe { ctx.pop() }
+        assert_eq!(ctx.len(), i);
+        assert_eq!(ctx.allocated_blocks(), 3);
+    }
+
+    assert!(ctx.is_empty());
+    assert!(ContextExt::is_empty(&ctx));
+
+    for i in 1..=12 {
+        ctx.push(i);
+    }
+
+    assert_eq!(ctx.allocated_blocks(), 3);
+    ctx.push(13);
+    assert_eq!(ctx.allocated_blocks(), 4);
+    assert_eq!(ctx.len(), 13);
+}
+
+#[test]
+fn local_len() {
+    thread_local! {
+        static CONTEXT: Context<i32> = Context::new(2);
+    }
+
+    assert!(CONTEXT.is_empty());
+
+    {
+        push!(let _a: CONTEXT = 0);
+        push!(let _b: CONTEXT = 1);
+        push!(let _c: CONTEXT = 2);
+        assert_eq!(CONTEXT.len(), 3);
+    }
+
+    assert!(CONTEXT.is_empty());
+    assert_eq!(CONTEXT.with(|ctx| ctx.allocated_blocks()), 2);
+}
+
+#[test]
+fn shrink_to_fit() {
+    let ctx = Context::new(4);
+
+    for i in 0..10 {
+        ctx.push(i);
+    }
+
+    for _ in 0..5 {
+        unsafe { ctx.pop() }
+    }
+
+    assert_eq!(ctx.allocated_blocks(), 3);
+    let memory_usage = ctx.memory_usage();
+    assert!(memory_usage >= 12 * core::mem::size_of::<i32>());
+
+    ctx.shrink_to_fit();
+    assert_eq!(ctx.allocated_blocks(), 2);
+    assert!(ctx.memory_usage() < memory_usage);
+    assert!(unsafe { ctx.iter_rev() }.copied().eq(0..5));
+
+    while !ctx.is_empty() {
+        unsafe { ctx.pop() }
+    }
+
+    ctx.shrink_to_fit();
+    assert_eq!(ctx.allocated_blocks(), 0);
+    assert_eq!(ctx.memory_usage(), 0);
+
+    ctx.push(0);
+    assert_eq!(ctx.allocated_blocks(), 1);
+}
+
+#[test]
+fn max_spare_blocks() {
+    let drops = Cell::new(0);
+    let ctx = Context::with_max_spare_blocks(2, 1);
+
+    for _ in 0..10 {
+        ctx.push(DropCounter(&drops));
+    }
+
+    assert_eq!(ctx.allocated_blocks(), 5);
+
+    for (i, blocks) in [5, 5, 5, 4, 4, 3, 3, 2, 2, 1].iter().enumerate() {
+        unsafe { ctx.pop() }
+        assert_eq!(drops.get(), i + 1);
+        assert_eq!(ctx.allocated_blocks(), *blocks, "{}", i);
+    }
+
+    let ctx = Context::with_max_spare_blocks(2, 0);
+    ctx.push(0);
+    ctx.push(1);
+    ctx.push(2);
+    assert_eq!(ctx.allocated_blocks(), 2);
+    unsafe { ctx.pop() }
+    assert_eq!(ctx.allocated_blocks(), 1);
+    unsafe { ctx.pop() }
+    unsafe { ctx.pop() }
+    assert_eq!(ctx.allocated_blocks(), 0);
+}
+
+#[test]
+fn new_const() {
+    thread_local! {
+        static CONTEXT: Context<String> = const { Context::new_const::<2>() };
+    }
+
+    CONTEXT.scope("a".to_string(), |_| {
+        push!(let _b: CONTEXT = "b".to_string());
+        push!(let _c: CONTEXT = "c".to_string());
+        get!(let value: CONTEXT);
+        assert_eq!(value, "c");
+        assert_eq!(CONTEXT.with(|ctx| ctx.allocated_blocks()), 2);
+    });
+
+    assert!(CONTEXT.is_empty());
+}
+
+#[test]
+fn inline_context() {
+    let drops = Cell::new(0);
+    let ctx = InlineContext::<_, 3>::new_inline(2);
+
+    for i in 1..=3 {
+        ctx.push((i, DropCounter(&drops)));
+    }
+
+    assert_eq!(ctx.allocated_blocks(), 0);
+    assert_eq!(ctx.memory_usage(), 0);
+    assert_eq!(ctx.capacity(), 3);
+
+    for i in 4..=7 {
+        ctx.push((i, DropCounter(&drops)));
+    }
+
+    assert_eq!(ctx.allocated_blocks(), 2);
+    assert_eq!(ctx.capacity(), 7);
+    assert!(unsafe { ctx.iter() }.map(|&(i, _)| i).eq((1..=7).rev()));
+
+    for _ in 0..5 {
+        unsafe { ctx.pop() }
+    }
+
+    assert_eq!(drops.get(), 5);
+    assert_eq!(unsafe { ctx.top().unwrap().as_ref().0 }, 2);
+
+    ctx.shrink_to_fit();
+    assert_eq!(ctx.allocated_blocks(), 0);
+
+    drop(ctx);
+    assert_eq!(drops.get(), 7);
+}
+
+#[test]
+fn inline_local() {
+    thread_local! {
+        static CONTEXT: InlineContext<i32, 2> = const { InlineContext::new_inline_const::<2>() };
+    }
+
+    CONTEXT.scope(0, |_| {
+        push!(let _a: CONTEXT = 1);
+        assert_eq!(CONTEXT.with(|ctx| ctx.allocated_blocks()), 0);
+        push!(let _b: CONTEXT = 2);
+        get!(let value: CONTEXT);
+        assert_eq!(*value, 2);
+        get_all!(let values: CONTEXT);
+        assert!(values.copied().eq([2, 1, 0].iter().copied()));
+        assert_eq!(CONTEXT.with(|ctx| ctx.allocated_blocks()), 1);
+    });
+
+    assert!(CONTEXT.is_empty());
+}
+
+#[test]
+fn get_mut() {
+    thread_local! {
+        static CONTEXT: Context<Vec<i32>> = Context::new(2);
+    }
+
+    fn collect(value: i32) {
+        get_mut!(let values: CONTEXT);
+        values.push(value);
+    }
+
+    let mut values = Some(Vec::new());
+
+    CONTEXT.lend(&mut values, || {
+        collect(1);
+        CONTEXT.scope(vec![], |_| {
+            get_all!(let all: CONTEXT);
+            assert_eq!(all.len(), 2);
+        });
+        collect(2);
+
+        get_mut!(let values: CONTEXT);
+        values.push(3);
+        CONTEXT.scope(vec![], |inner| assert!(inner.is_empty()));
+        values.push(4);
+    });
+
+    assert_eq!(values, Some(vec![1, 2, 3, 4]));
+}
+
+#[test]
+fn get_mut_aliasing() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    thread_local! {
+        static CONTEXT: Context<i32> = Context::new(2);
+    }
+
+    fn get() -> i32 {
+        get!(let value: CONTEXT);
+        *value
+    }
+
+    fn get_all() -> usize {
+        get_all!(let values: CONTEXT);
+        values.len()
+    }
+
+    fn set(value: i32) {
+        get_mut!(let top: CONTEXT);
+        *top = value;
+    }
+
+    CONTEXT.lend(&mut Some(0), || {
+        {
+            get!(let _value: CONTEXT);
+            assert!(catch_unwind(|| set(1)).is_err());
+        }
+
+        set(1);
+
+        get_mut!(let value: CONTEXT);
+        assert!(catch_unwind(get).is_err());
+        assert!(catch_unwind(get_all).is_err());
+
+        CONTEXT.scope(2, |_| {
+            assert_eq!(get(), 2);
+            assert!(catch_unwind(get_all).is_err());
+            assert!(catch_unwind(|| set(3)).is_err());
+        });
+
+        *value += 10;
+    });
+
+    push!(let value: CONTEXT = 0);
+    assert!(catch_unwind(AssertUnwindSafe(|| set(1))).is_err());
+    assert_eq!(*value, 0);
+    assert_eq!(get(), 0);
+    assert_eq!(get_all(), 1);
+}
+
+#[test]
+fn item_get_mut() {
+    let ctx = Context::new(2);
+    let pin = unsafe { StackPin::new() };
+    let mut item = unsafe { Item::from_ref(&ctx, &pin, 0) };
+
+    *item.get_mut() += 1;
+    assert_eq!(*item, 1);
+
+    {
+        let _guard = item.guard();
+        let pin = unsafe { StackPin::new() };
+        let result = unsafe { StackGuardMut::from_ref(&ctx, &pin) };
+        assert_eq!(result.err(), Some(ContextError::Borrowed));
+    }
+
+    let mut values = Some(10);
+    ctx.lend(&mut values, || {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(item.get_mut())));
+        assert!(result.is_err());
+    });
+
+    *item.get_mut() += 1;
+    assert_eq!(*item, 2);
+}
+
+#[test]
+fn with_modified() {
+    thread_local! {
+        static DEPTH: Context<usize> = Context::new(2);
+    }
+
+    fn depth() -> usize {
+        get!(let depth: DEPTH);
+        *depth
+    }
+
+    fn recurse(n: usize) -> usize {
+        if n == 0 {
+            depth()
+        } else {
+            DEPTH.with_modified(|depth| depth + 1, |_| recurse(n - 1))
+        }
+    }
+
+    DEPTH.scope(0, |_| {
+        assert_eq!(recurse(5), 5);
+        assert_eq!(depth(), 0);
+        assert_eq!(DEPTH.with_modified(|depth| depth + 10, |&depth| depth), 10);
+    });
+
+    let result = std::panic::catch_unwind(|| DEPTH.with_modified(|depth| depth + 1, |_| ()));
+    assert!(result.is_err());
+}
+
+#[test]
+fn get_all_moved() {
+    thread_local! {
+        static CONTEXT: Context<u32> = Context::new(2);
+    }
+
+    CONTEXT.lend(&mut Some(1), || {
+        get_all!(let values: CONTEXT);
+        let value = {
+            let moved = values;
+            moved.next().unwrap()
+        };
+
+        // moving the binding only moves a reference, the iterator keeps borrowing the context
+        CONTEXT.with(|ctx| assert_eq!(ctx.borrow.top.get(), 1));
+        assert_eq!(*value, 1);
+    });
+}
+
+#[test]
+fn replace_top() {
+    let ctx = Context::new(2);
+
+    ctx.lend(&mut Some(1), || {
+        let len = ctx.len();
+        let allocated = ctx.allocated_blocks();
+
+        {
+            let guard = ctx.replace_top(2);
+            assert_eq!(*guard.original(), 1);
+            assert_eq!(unsafe { *ctx.top().unwrap().as_ref() }, 2);
+            assert_eq!(ctx.len(), len);
+
+            ctx.lend(&mut Some(3), || {
+                let _guard = ctx.replace_top(4);
+                assert_eq!(unsafe { *ctx.top().unwrap().as_ref() }, 4);
+            });
+
+            assert_eq!(unsafe { *ctx.top().unwrap().as_ref() }, 2);
+        }
+
+        assert_eq!(unsafe { *ctx.top().unwrap().as_ref() }, 1);
+        assert_eq!(ctx.allocated_blocks(), allocated);
+    });
+
+    ctx.scope(0, |_| {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(ctx.replace_top(1))));
+        assert!(result.is_err());
+    });
+}
+
+#[test]
+fn replace_top_get_all_moved() {
+    thread_local! {
+        static CONTEXT: Context<u32> = Context::new(2);
+    }
+
+    CONTEXT.with(|ctx| {
+        ctx.lend(&mut Some(1), || {
+            let guard = ctx.replace_top(2);
+            get_all!(let values: CONTEXT);
+            let value = {
+                let moved = values;
+                moved.next().unwrap()
+            };
+
+            // the iterator still borrows the replaced value, so it can't be restored yet
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(guard)));
+            assert!(result.is_err());
+            assert_eq!(*value, 2);
+        })
+    });
+}
+
+#[test]
+fn sync_context() {
+    static CONTEXT: SyncContext<String> = SyncContext::new(2);
+    static OTHER: SyncContext<String> = SyncContext::new(2);
+
+    fn get() -> Option<String> {
+        try_get!(let value: CONTEXT);
+        value.ok().cloned()
+    }
+
+    push!(let _value: CONTEXT = "main".to_string());
+    push!(let _other: OTHER = "other".to_string());
+
+    let threads = (0..4)
+        .map(|i| {
+            std::thread::spawn(move || {
+                assert_eq!(get(), None);
+                CONTEXT.scope(format!("thread {}", i), |_| {
+                    push!(let _value: CONTEXT = format!("nested {}", i));
+                    assert_eq!(get(), Some(format!("nested {}", i)));
+                    assert_eq!(CONTEXT.len(), 2);
+                    assert!(OTHER.is_empty());
+                });
+                assert!(CONTEXT.is_empty());
+            })
+        })
+        .collect::<Vec<_>>();
+
+    threads.into_iter().for_each(|thread| thread.join().unwrap());
+
+    assert_eq!(get(), Some("main".to_string()));
+    get!(let other: OTHER);
+    assert_eq!(other, "other");
+}
+
+#[test]
+fn default_value() {
+    thread_local! {
+        static DEPTH: Context<usize> = Context::new(2).with_default(0);
+    }
+
+    fn depth() -> usize {
+        get!(let depth: DEPTH);
+        *depth
+    }
+
+    assert_eq!(depth(), 0);
+    assert!(DEPTH.is_empty());
+
+    DEPTH.with_modified(|depth| depth + 1, |_| {
+        assert_eq!(depth(), 1);
+        get_all!(let values: DEPTH);
+        assert!(values.copied().eq([1].iter().copied()));
+        DEPTH.restore(&Context::new(1).snapshot(), || {
+            try_get!(let depth: DEPTH);
+            assert_eq!(depth, Err(ContextError::Empty));
+        });
+    });
+
+    {
+        get_mut!(let depth: DEPTH);
+        *depth = 5;
+    }
+
+    assert_eq!(depth(), 5);
+
+    let drops = Cell::new(0);
+    let ctx = Context::new(2).with_default(DropCounter(&drops));
+    ctx.scope(DropCounter(&drops), |_| ());
+    assert_eq!(drops.get(), 1);
+    drop(ctx);
+    assert_eq!(drops.get(), 2);
+}
+
+#[test]
+fn restore_hides_default() {
+    thread_local! {
+        static DEPTH: Context<usize> = Context::new(2).with_default(0);
+    }
+
+    get_mut!(let outer: DEPTH);
+
+    DEPTH.with(|ctx| {
+        ctx.restore(&Snapshot::default(), || {
+            try_get!(let inner: DEPTH);
+            assert_eq!(inner, Err(ContextError::Empty));
+
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(ctx.replace_top(1))));
+            assert!(result.is_err());
+
+            ctx.lend(&mut Some(2), || {
+                get_mut!(let inner: DEPTH);
+                *inner += 1;
+            });
+        });
+
+        assert_eq!(format!("{:?}", ctx), "Context { values: <mutably borrowed>, default: <mutably borrowed> }");
+    });
+
+    *outer += 1;
+}
+
+#[test]
+fn context_error() {
+    thread_local! {
+        static CONTEXT: Context<u32> = Context::new(2);
+    }
+
+    try_get!(let value: CONTEXT);
+    assert_eq!(value, Err(ContextError::Empty));
+
+    try_get_mut!(let value: CONTEXT);
+    assert_eq!(value, Err(ContextError::Empty));
+
+    CONTEXT.lend(&mut Some(1), || {
+        {
+            get_mut!(let _value: CONTEXT);
+            try_get!(let value: CONTEXT);
+            assert_eq!(value, Err(ContextError::BorrowedMutably));
+            try_get_mut!(let value: CONTEXT);
+            assert_eq!(value, Err(ContextError::BorrowedMutably));
+        }
+
+        {
+            get!(let _value: CONTEXT);
+            try_get_mut!(let value: CONTEXT);
+            assert_eq!(value, Err(ContextError::Borrowed));
+        }
+
+        {
+            try_get_mut!(let value: CONTEXT);
+            *value.unwrap() += 1;
+        }
+
+        get!(let value: CONTEXT);
+        assert_eq!(*value, 2);
+    });
+
+    let result = std::panic::catch_unwind(|| {
+        get!(let _value: CONTEXT);
+    });
+    let error = result.unwrap_err();
+    assert_eq!(error.downcast_ref::<String>().unwrap(), "Tried to get from an empty context");
+}
+
+#[test]
+fn thread_local_destruction() {
+    use std::sync::{Arc, Mutex};
+
+    type Log = Arc<Mutex<Vec<(Result<(), ContextError>, usize, usize)>>>;
+
+    // accesses the context which owns it when dropped, while the thread is exiting
+    struct Reentrant(Option<Log>, bool);
+
+    thread_local! {
+        static CONTEXT: Context<Reentrant> = Context::new(2);
+    }
+
+    static SYNC: SyncContext<Reentrant> = SyncContext::new(2);
+
+    macro_rules! check {
+        ($context:ident) => {{
+            try_get!(let value: $context);
+            let value = value.map(drop);
+            {
+                push!(let _value: $context = Reentrant(None, false));
+            }
+            get_all!(let values: $context);
+            let len = $context.scope(Reentrant(None, false), |_| $context.len());
+            (value, values.len(), len)
+        }};
+    }
+
+    impl Drop for Reentrant {
+        fn drop(&mut self) {
+            if let Some(log) = self.0.take() {
+                let result = if self.1 { check!(SYNC) } else { check!(CONTEXT) };
+                log.lock().unwrap().push(result);
+            }
+        }
+    }
+
+    // accesses other contexts from a thread local destructor, which may run before or after they're destroyed
+    struct Guard;
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            crate::context! {
+                static NAME: String = String::new();
+            }
+
+            let _pair = mdc::push("key", "value");
+            mdc::get("key");
+            mdc::pairs();
+
+            let _name = NAME.push("name".to_string());
+            NAME.scope("scoped".to_string(), |_| NAME.try_get(|name| name.is_ok()));
+        }
+    }
+
+    thread_local! {
+        static GUARD: Guard = const { Guard };
+    }
+
+    let log = Log::default();
+    let thread_log = log.clone();
+
+    std::thread::spawn(move || {
+        GUARD.with(|_| ());
+        CONTEXT.push(Reentrant(Some(thread_log.clone()), false));
+        SYNC.push(Reentrant(Some(thread_log), true));
+    })
+    .join()
+    .unwrap();
+
+    let log = log.lock().unwrap();
+    assert_eq!(*log, [(Err(ContextError::Destroyed), 0, 0), (Err(ContextError::Destroyed), 0, 0)]);
+}
+
+#[test]
+fn debug() {
+    let ctx = Context::new(2);
+    assert_eq!(format!("{:?}", ctx), "Context { values: [] }");
+
+    ctx.scope(1, |_| {
+        ctx.scope(2, |_| assert_eq!(format!("{:?}", ctx), "Context { values: [2, 1] }"));
+
+        ctx.lend(&mut Some(3), || {
+            let stack_pin = unsafe { StackPin::new() };
+            let _value = unsafe { StackGuardMut::from_ref(&ctx, &stack_pin) };
+            assert_eq!(format!("{:?}", ctx), "Context { values: <mutably borrowed> }");
+        });
+    });
+
+    let ctx = ctx.with_default(0);
+    assert_eq!(format!("{:?}", ctx), "Context { values: [], default: 0 }");
+    ctx.restore(&Snapshot::default(), || {
+        ctx.scope(4, |_| assert_eq!(format!("{:?}", ctx), "Context { values: [4] }"));
+    });
+}
+
+#[test]
+fn depth_and_occupancy() {
+    let ctx = Context::<u32>::new(3);
+    assert_eq!(ctx.occupancy().count(), 0);
+
+    for i in 0..5 {
+        ctx.push(i);
+    }
+
+    unsafe { ctx.truncate(4) }
+    ctx.restore(&ctx.snapshot(), || {
+        assert_eq!(ctx.len(), 4);
+        assert_eq!(ctx.depth(), 8);
+        assert_eq!(ctx.max_depth_seen(), 8);
+    });
+
+    assert_eq!(ctx.depth(), 4);
+    assert_eq!(ctx.max_depth_seen(), 8);
+
+    let block = |len| Occupancy {
+        len,
+        capacity: 3,
+        inline: false,
+    };
+    assert!(ctx.occupancy().eq([block(3), block(1), block(0)].iter().copied()));
+
+    let ctx = InlineContext::<u32, 2>::new_inline(3);
+    ctx.push(0);
+    let inline = Occupancy {
+        len: 1,
+        capacity: 2,
+        inline: true,
+    };
+    assert!(ctx.occupancy().eq([inline].iter().copied()));
+}
+
+#[test]
+fn max_depth() {
+    thread_local! {
+        static DEPTH: Context<usize> = Context::new(2).with_default(0).with_max_depth(3, OverflowPolicy::Panic);
+    }
+
+    fn recurse() -> usize { DEPTH.with_modified(|depth| depth + 1, |_| recurse()) }
+
+    let result = std::panic::catch_unwind(recurse);
+    let error = result.unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<String>().unwrap(),
+        "Tried to push onto a context at its max depth of 3"
+    );
+    assert!(DEPTH.is_empty());
+
+    DEPTH.with(|ctx| {
+        assert_eq!(ctx.max_depth(), Some(3));
+        assert_eq!(ctx.max_depth_seen(), 3);
+
+        ctx.scope(1, |_| {
+            let snapshot = ctx.snapshot();
+            ctx.restore(&snapshot, || {
+                assert_eq!(ctx.len(), 1);
+                ctx.try_push(2).unwrap();
+                assert_eq!(ctx.try_push(3).unwrap_err(), (3, PushError::DepthExceeded { max_depth: 3 }));
+            });
+        });
+    });
+
+    assert_eq!(Context::<u32>::new(2).max_depth(), None);
+}
+
+#[test]
+fn failed_allocation() {
+    thread_local! {
+        // a block this large overflows `isize::MAX` bytes, so it can never be allocated
+        static HUGE: Context<u64, 1> = Context::new_inline(usize::MAX / 4);
+    }
+
+    HUGE.with(|ctx| {
+        ctx.try_push(0).unwrap();
+        assert_eq!(ctx.try_push(1).unwrap_err(), (1, PushError::Alloc(AllocError)));
+        assert_eq!(ctx.len(), 1);
+        assert_eq!(ctx.capacity(), 1);
+    });
+
+    try_push!(let value: HUGE = 1);
+    assert_eq!(value, Err((1, PushError::Alloc(AllocError))));
+    get!(let top: HUGE);
+    assert_eq!(*top, 0);
+}
@@ -0,0 +1,281 @@
+use crate::{with_context_or, Context, ContextError, ContextKey, StackGuard, StackPin};
+use std::{marker::PhantomData, thread::LocalKey};
+
+/// Defines thread local contexts, each with a [`LocalContext`] handle
+///
+/// Every context can have a default value, which is used when nothing else has been pushed,
+/// and a block capacity, which defaults to 16
+///
+/// ```
+/// contextual::context! {
+///     /// The id of the request being handled
+///     pub static REQUEST_ID: u64;
+///
+///     pub(crate) static LOCALE: String = "en".to_string();
+///
+///     static DEPTH: u32 = 0, block_capacity = 64;
+/// }
+///
+/// assert_eq!(REQUEST_ID.try_get(|id| id.ok().copied()), None);
+///
+/// REQUEST_ID.scope(10, |_| {
+///     assert_eq!(REQUEST_ID.get(|id| *id), 10);
+/// });
+///
+/// assert_eq!(LOCALE.get(String::clone), "en");
+///
+/// let _locale = LOCALE.push("fr".to_string());
+/// assert_eq!(LOCALE.get(String::clone), "fr");
+/// ```
+#[macro_export]
+macro_rules! context {
+    () => {};
+    (@block_capacity) => { 16 };
+    (@block_capacity $block_capacity:expr) => { $block_capacity };
+    (
+        $(#[$attr:meta])*
+        $vis:vis static $name:ident: $type:ty $(= $default:expr)? $(, block_capacity = $block_capacity:expr)?;
+        $($rest:tt)*
+    ) => {
+        $(#[$attr])*
+        $vis static $name: $crate::LocalContext<$type> = {
+            $crate::macros::thread_local! {
+                static CONTEXT: $crate::Context<$type> = {
+                    let context = $crate::Context::new($crate::context!(@block_capacity $($block_capacity)?));
+                    $(let context = context.with_default($default);)?
+                    context
+                };
+            }
+
+            $crate::LocalContext::new(&CONTEXT)
+        };
+
+        $crate::context!($($rest)*);
+    };
+}
+
+/// A handle to a thread local context, which can be used without the macros
+///
+/// Usually defined with [`context!`](crate::context)
+pub struct LocalContext<T: 'static, const N: usize = 0> {
+    key: &'static LocalKey<Context<T, N>>,
+}
+
+/// Pops a value from a context when dropped
+///
+/// Created by [`LocalContext::push`]
+///
+/// Guards must be dropped in the reverse order they were created. If the value is no
+/// longer on the top of the context, or is still borrowed, it isn't popped and stays on
+/// the context until the thread exits. This panics in debug builds, unless the thread
+/// is already panicking.
+///
+/// Guards must also be dropped before any [`scope`](LocalContext::scope) they were
+/// created in returns, otherwise the scope panics and leaks its own value.
+#[must_use = "the value is popped as soon as the guard is dropped"]
+pub struct Pushed<T: 'static, const N: usize = 0> {
+    key: &'static LocalKey<Context<T, N>>,
+    len: usize,
+    borrow: isize,
+    // the value is on this thread's context
+    not_send: PhantomData<*const ()>,
+}
+
+impl<T, const N: usize> LocalContext<T, N> {
+    pub const fn new(key: &'static LocalKey<Context<T, N>>) -> Self { Self { key } }
+
+    pub fn key(&self) -> &'static LocalKey<Context<T, N>> { self.key }
+
+    pub fn len(&self) -> usize { self.key.try_with_context(Context::len).unwrap_or(0) }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Pushes a value onto the context until the returned guard is dropped
+    ///
+    /// Nothing else holds a reference to the value, so it can be accessed with [`get_mut!`](crate::get_mut)
+    ///
+    /// If the context has been destroyed, the value is dropped immediately
+    pub fn push(&self, value: T) -> Pushed<T, N> {
+        let pushed = self.key.try_with_context(move |ctx| {
+            ctx.push(value);
+            (ctx.len.get(), ctx.borrow.enter(0))
+        });
+        // no value has a length of zero, so the guard won't pop anything
+        let (len, borrow) = pushed.unwrap_or((0, 0));
+
+        Pushed {
+            key: self.key,
+            len,
+            borrow,
+            not_send: PhantomData,
+        }
+    }
+
+    /// Pushes a value onto the context for the duration of `f`
+    ///
+    /// If the context has been destroyed, `f` is called without pushing the value
+    pub fn scope<R>(&self, value: T, f: impl FnOnce(&T) -> R) -> R {
+        with_context_or(self.key, (value, f), |ctx, (value, f)| ctx.scope(value, f), |(value, f)| f(&value))
+    }
+
+    /// Calls `f` with the top value of the context
+    ///
+    /// # Panics
+    ///
+    /// If the context is empty, or if the top value is mutably borrowed
+    pub fn get<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        self.try_get(move |value| f(value.unwrap_or_else(|error| panic!("{}", error))))
+    }
+
+    /// Calls `f` with the top value of the context, or the reason it can't be accessed
+    pub fn try_get<R>(&self, f: impl FnOnce(Result<&T, ContextError>) -> R) -> R {
+        let stack_pin = unsafe { StackPin::new() };
+        let value = unsafe { StackGuard::new(self.key, &stack_pin) };
+        f(value.as_deref().map_err(|&error| error))
+    }
+}
+
+impl<T, const N: usize> ContextKey<T, N> for LocalContext<T, N> {
+    fn try_with_context<R>(&'static self, f: impl FnOnce(&Context<T, N>) -> R) -> Result<R, ContextError> {
+        self.key.try_with_context(f)
+    }
+
+    #[cfg(feature = "tokio")]
+    fn task_key(&'static self) -> Option<usize> { self.key.task_key() }
+}
+
+impl<T, const N: usize> Drop for Pushed<T, N> {
+    fn drop(&mut self) {
+        if self.len == 0 {
+            return
+        }
+
+        let _ = self.key.try_with_context(|ctx| {
+            // values below the base are hidden by a restore, and their borrows aren't counted in `top`
+            let on_top = ctx.len.get() == self.len && self.len > ctx.base.get() && ctx.borrow.top.get() == 0;
+            debug_assert!(
+                on_top || std::thread::panicking(),
+                "Tried to pop a context value which is no longer on the top of the context, or is still borrowed"
+            );
+
+            if on_top {
+                ctx.borrow.exit(self.borrow);
+                unsafe { ctx.pop() }
+            }
+        });
+    }
+}
+
+#[test]
+fn context_macro() {
+    crate::context! {
+        /// Attributes are passed through
+        #[doc(alias = "name")]
+        static NAME: String;
+
+        pub(crate) static DEPTH: u32 = 0;
+        static BLOCKS: u8 = 0, block_capacity = 2;
+        static EMPTY: u8, block_capacity = 3;
+    }
+
+    assert!(NAME.is_empty());
+    assert_eq!(NAME.try_get(|name| name.cloned()), Err(ContextError::Empty));
+    assert_eq!(DEPTH.get(|depth| *depth), 0);
+
+    NAME.scope("outer".to_string(), |outer| {
+        let _inner = NAME.push("inner".to_string());
+        assert_eq!(NAME.len(), 2);
+        assert_eq!(outer, "outer");
+
+        {
+            get_mut!(let name: NAME);
+            name.push_str(" (modified)");
+        }
+
+        assert_eq!(NAME.get(String::clone), "inner (modified)");
+    });
+
+    assert!(NAME.is_empty());
+
+    {
+        let _depth = DEPTH.push(DEPTH.get(|depth| depth + 1));
+        get!(let depth: DEPTH);
+        assert_eq!(*depth, 1);
+    }
+
+    assert_eq!(DEPTH.get(|depth| *depth), 0);
+
+    assert!(BLOCKS.is_empty());
+    assert_eq!(BLOCKS.get(|value| *value), 0);
+    BLOCKS.scope(1, |_| BLOCKS.key().with(|ctx| assert_eq!(ctx.capacity(), 2)));
+    EMPTY.key().with(|ctx| assert_eq!(ctx.capacity(), 0));
+
+    let pushed = (0..3).map(|i| EMPTY.push(i)).collect::<Vec<_>>();
+    EMPTY.key().with(|ctx| assert_eq!(ctx.capacity(), 3));
+    pushed.into_iter().rev().for_each(drop);
+    assert!(EMPTY.is_empty());
+}
+
+#[test]
+fn pushed_out_of_order() {
+    crate::context! {
+        static VALUES: u32;
+    }
+
+    let first = VALUES.push(0);
+    let second = VALUES.push(1);
+
+    let result = std::panic::catch_unwind(move || drop(first));
+    assert_eq!(result.is_err(), cfg!(debug_assertions));
+
+    drop(second);
+    assert_eq!(VALUES.len(), 1);
+
+    // the guards are dropped in the order they were pushed while unwinding, which doesn't abort
+    let result = std::panic::catch_unwind(|| {
+        let _pushed = [VALUES.push(2), VALUES.push(3)];
+        panic!()
+    });
+    assert!(result.is_err());
+    assert_eq!(VALUES.len(), 2);
+}
+
+#[test]
+fn pushed_during_restore() {
+    crate::context! {
+        static VALUES: u32;
+    }
+
+    let pushed = VALUES.push(0);
+    get!(let value: VALUES);
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        VALUES.key().with(|ctx| ctx.restore(&crate::Snapshot::default(), move || drop(pushed)))
+    }));
+    assert_eq!(result.is_err(), cfg!(debug_assertions));
+
+    // the value is hidden by the restore, so it isn't popped while it's still borrowed
+    assert_eq!(VALUES.len(), 1);
+    assert_eq!(*value, 0);
+}
+
+#[test]
+fn pushed_escaped() {
+    crate::context! {
+        static VALUES: u32;
+    }
+
+    // the guards outlive the scopes, which can't pop the values they pushed
+    let result = std::panic::catch_unwind(|| VALUES.scope(0, |_| std::mem::forget(VALUES.push(1))));
+    assert!(result.is_err());
+    assert_eq!(VALUES.len(), 2);
+    assert_eq!(VALUES.get(|value| *value), 1);
+
+    let mut lent = Some(2);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        VALUES.key().with(|ctx| ctx.lend(&mut lent, || std::mem::forget(VALUES.push(3))))
+    }));
+    assert!(result.is_err());
+    assert_eq!(lent, None);
+    assert_eq!(VALUES.len(), 4);
+}
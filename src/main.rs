@@ -1,4 +1,4 @@
-use contextual::{get, push, Context, ContextExt as _};
+use contextual::{Context, ContextExt as _};
 
 thread_local! {
     static CTX: Context<u32> = Context::new(16);
@@ -6,9 +6,4 @@ thread_local! {
 
 fn main() {
     CTX.push(0);
-    // println!("{}", main);
-}
-
-fn fib() {
-    // println!("{}", main);
 }
@@ -0,0 +1,250 @@
+//! A mapped diagnostic context, key value pairs which describe the current scope
+//!
+#![cfg_attr(feature = "log", doc = "With the `log` feature, [`MdcLogger`] adds the active pairs to every log record")]
+#![cfg_attr(not(feature = "log"), doc = "With the `log` feature, `MdcLogger` adds the active pairs to every log record")]
+//!
+//! ```
+//! use contextual::mdc;
+//!
+//! let _request = mdc::push("request_id", "abc");
+//!
+//! mdc::scope("user", "alice", || {
+//!     assert_eq!(mdc::get("user").as_deref(), Some("alice"));
+//!     assert_eq!(mdc::pairs(), [("request_id", "abc".to_string()), ("user", "alice".to_string())]);
+//! });
+//!
+//! assert_eq!(mdc::get("user"), None);
+//! ```
+
+use crate::Context;
+use std::{cell::Cell, marker::PhantomData};
+
+thread_local! {
+    static MDC: Context<Pair> = Context::new(16);
+    static NEXT_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+struct Pair {
+    key: &'static str,
+    value: String,
+    /// identifies the guard which pushed the pair
+    id: u64,
+}
+
+/// Removes a pair from the mdc when dropped
+///
+/// Created by [`push()`]
+#[must_use = "the pair is removed as soon as the guard is dropped"]
+pub struct MdcGuard {
+    len: usize,
+    id: u64,
+    // the pair is on this thread's mdc
+    not_send: PhantomData<*const ()>,
+}
+
+/// Adds a pair to the mdc until the returned guard is dropped
+///
+/// If the guard of an outer pair is dropped first, all pairs pushed after it are removed too,
+/// and dropping their guards afterwards does nothing
+///
+/// While the thread is exiting the mdc may have been destroyed, then the pair is ignored
+pub fn push(key: &'static str, value: impl Into<String>) -> MdcGuard {
+    let value = value.into();
+    let id = NEXT_ID.try_with(|id| id.replace(id.get() + 1)).unwrap_or(0);
+    let len = MDC.try_with(move |mdc| {
+        mdc.push(Pair { key, value, id });
+        mdc.len()
+    });
+    // no pair has a length of zero, so the guard won't remove anything
+    let len = len.unwrap_or(0);
+
+    MdcGuard {
+        len,
+        id,
+        not_send: PhantomData,
+    }
+}
+
+/// Adds a pair to the mdc for the duration of `f`
+pub fn scope<R>(key: &'static str, value: impl Into<String>, f: impl FnOnce() -> R) -> R {
+    let _guard = push(key, value);
+    f()
+}
+
+/// The innermost value of `key`
+pub fn get(key: &str) -> Option<String> {
+    get_all!(let pairs: MDC);
+    for pair in pairs {
+        if pair.key == key {
+            return Some(pair.value.clone())
+        }
+    }
+    None
+}
+
+/// All active pairs from the outermost to the innermost, without pairs shadowed by an inner pair with the same key
+pub fn pairs() -> Vec<(&'static str, String)> {
+    get_all!(let all: MDC);
+
+    let mut pairs = Vec::<(&'static str, String)>::new();
+    for pair in all {
+        if pairs.iter().all(|&(key, _)| key != pair.key) {
+            pairs.push((pair.key, pair.value.clone()));
+        }
+    }
+
+    pairs.reverse();
+    pairs
+}
+
+impl Drop for MdcGuard {
+    fn drop(&mut self) {
+        let len = self.len;
+        let _ = MDC.try_with(|mdc| {
+            // references to the pairs never escape this module, and are never held
+            // while pushing, so the pairs can be removed in any order
+            if len != 0 && mdc.len() >= len && unsafe { (*mdc.slot(len - 1)).id } == self.id {
+                unsafe { mdc.truncate(len - 1) }
+            }
+        });
+    }
+}
+
+/// A logger which adds the active mdc pairs to the key values of every record
+#[cfg(feature = "log")]
+pub struct MdcLogger<L> {
+    inner: L,
+}
+
+#[cfg(feature = "log")]
+struct KeyValues<'a> {
+    record: &'a dyn log::kv::Source,
+    pairs: &'a [(&'static str, String)],
+}
+
+#[cfg(feature = "log")]
+impl<L: log::Log> MdcLogger<L> {
+    pub fn new(inner: L) -> Self { Self { inner } }
+
+    pub fn inner(&self) -> &L { &self.inner }
+
+    pub fn into_inner(self) -> L { self.inner }
+}
+
+#[cfg(feature = "log")]
+impl log::kv::Source for KeyValues<'_> {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn log::kv::VisitSource<'kvs>) -> Result<(), log::kv::Error> {
+        self.record.visit(visitor)?;
+
+        for (key, value) in self.pairs {
+            visitor.visit_pair(log::kv::Key::from_str(key), log::kv::Value::from(value.as_str()))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "log")]
+impl<L: log::Log> log::Log for MdcLogger<L> {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool { self.inner.enabled(metadata) }
+
+    fn log(&self, record: &log::Record<'_>) {
+        // the pairs are collected first, so the mdc isn't borrowed if the inner logger pushes to it
+        let pairs = pairs();
+        if pairs.is_empty() {
+            return self.inner.log(record)
+        }
+
+        let key_values = KeyValues {
+            record: record.key_values(),
+            pairs: &pairs,
+        };
+
+        self.inner.log(&record.to_builder().key_values(&key_values).build())
+    }
+
+    fn flush(&self) { self.inner.flush() }
+}
+
+#[test]
+fn mdc() {
+    assert!(pairs().is_empty());
+
+    let request = push("request_id", "abc");
+
+    scope("user", "alice", || {
+        let _user = push("user", "bob");
+        assert_eq!(get("user").as_deref(), Some("bob"));
+        assert_eq!(pairs(), [("request_id", "abc".to_string()), ("user", "bob".to_string())]);
+    });
+
+    let user = push("user", "carol");
+    drop(request);
+
+    assert!(pairs().is_empty());
+    drop(user);
+
+    let _request = push("request_id", "def");
+    assert_eq!(get("request_id").as_deref(), Some("def"));
+}
+
+#[test]
+fn stale_guard() {
+    let a = push("a", "1");
+    let b = push("b", "2");
+    drop(a);
+
+    let _c = push("c", "3");
+    let _d = push("d", "4");
+    drop(b);
+
+    assert_eq!(pairs(), [("c", "3".to_string()), ("d", "4".to_string())]);
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn mdc_logger() {
+    use log::Log;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Capture(Mutex<Vec<String>>);
+
+    impl Log for Capture {
+        fn enabled(&self, _: &log::Metadata<'_>) -> bool { true }
+
+        fn log(&self, record: &log::Record<'_>) {
+            struct Visitor(String);
+
+            impl<'kvs> log::kv::VisitSource<'kvs> for Visitor {
+                fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+                    self.0 += &format!(" {}={}", key, value);
+                    Ok(())
+                }
+            }
+
+            let mut visitor = Visitor(record.args().to_string());
+            record.key_values().visit(&mut visitor).unwrap();
+            self.0.lock().unwrap().push(visitor.0);
+        }
+
+        fn flush(&self) {}
+    }
+
+    let logger = MdcLogger::new(Capture::default());
+    let log = |args: std::fmt::Arguments<'_>, key_values: &[(&str, &str)]| {
+        logger.log(&log::Record::builder().args(args).key_values(&key_values).build())
+    };
+
+    log(format_args!("start"), &[]);
+
+    scope("request_id", "abc", || {
+        log(format_args!("handle"), &[("status", "200")]);
+        scope("request_id", "def", || log(format_args!("retry"), &[]));
+    });
+
+    assert_eq!(
+        *logger.inner().0.lock().unwrap(),
+        ["start", "handle status=200 request_id=abc", "retry request_id=def"]
+    );
+}
@@ -0,0 +1,210 @@
+//! Carry contexts into rayon's parallel iterators
+//!
+//! ```
+//! use contextual::{get, push, rayon::ParallelIteratorExt, Context};
+//! use rayon::prelude::*;
+//!
+//! thread_local! {
+//!     static SCALE: Context<u32> = Context::new(16);
+//! }
+//!
+//! push!(let _scale: SCALE = 10);
+//!
+//! let values: Vec<u32> = (0..4u32)
+//!     .into_par_iter()
+//!     .with_context(&SCALE)
+//!     .map(|x| {
+//!         get!(let scale: SCALE);
+//!         x * *scale
+//!     })
+//!     .collect();
+//!
+//! assert_eq!(values, [0, 10, 20, 30]);
+//! ```
+
+use crate::{Capture, Install};
+use rayon::iter::{
+    plumbing::{Consumer, Folder, Reducer, UnindexedConsumer},
+    ParallelIterator,
+};
+
+pub trait ParallelIteratorExt: ParallelIterator {
+    /// Captures the current values of `contexts`, and installs them on whichever
+    /// thread runs the rest of the parallel iterator
+    ///
+    /// The values are cloned once per job and lent to each item, so changes made
+    /// with `get_mut!` are seen by later items of the same job
+    fn with_context<C>(self, contexts: C) -> WithContext<Self, C::Captured>
+    where
+        C: Capture,
+        C::Captured: Clone + Send + Sync,
+    {
+        WithContext {
+            base: self,
+            captured: contexts.capture(),
+        }
+    }
+}
+
+impl<I: ParallelIterator> ParallelIteratorExt for I {}
+
+/// A parallel iterator which runs with captured contexts installed
+///
+/// Created by [`ParallelIteratorExt::with_context`]
+pub struct WithContext<I, C> {
+    base: I,
+    captured: C,
+}
+
+struct ContextConsumer<'c, B, C> {
+    base: B,
+    captured: &'c C,
+}
+
+// the folder owns its values, so they can be lent to each item instead of cloned
+struct ContextFolder<B, C> {
+    base: B,
+    captured: C,
+}
+
+struct ContextReducer<'c, B, C> {
+    base: B,
+    captured: &'c C,
+}
+
+impl<I, C> ParallelIterator for WithContext<I, C>
+where
+    I: ParallelIterator,
+    C: Install + Clone + Send + Sync,
+{
+    type Item = I::Item;
+
+    fn drive_unindexed<Co: UnindexedConsumer<Self::Item>>(self, consumer: Co) -> Co::Result {
+        self.base.drive_unindexed(ContextConsumer {
+            base: consumer,
+            captured: &self.captured,
+        })
+    }
+
+    fn opt_len(&self) -> Option<usize> { self.base.opt_len() }
+}
+
+impl<'c, T, B: Consumer<T>, C: Install + Clone + Sync> Consumer<T> for ContextConsumer<'c, B, C> {
+    type Folder = ContextFolder<B::Folder, C>;
+    type Reducer = ContextReducer<'c, B::Reducer, C>;
+    type Result = B::Result;
+
+    fn split_at(self, index: usize) -> (Self, Self, Self::Reducer) {
+        let (left, right, reducer) = self.base.split_at(index);
+        let captured = self.captured;
+        (
+            ContextConsumer { base: left, captured },
+            ContextConsumer { base: right, captured },
+            ContextReducer { base: reducer, captured },
+        )
+    }
+
+    fn into_folder(self) -> Self::Folder {
+        ContextFolder {
+            base: self.base.into_folder(),
+            captured: self.captured.clone(),
+        }
+    }
+
+    fn full(&self) -> bool { self.base.full() }
+}
+
+impl<T, B: UnindexedConsumer<T>, C: Install + Clone + Sync> UnindexedConsumer<T> for ContextConsumer<'_, B, C> {
+    fn split_off_left(&self) -> Self {
+        ContextConsumer {
+            base: self.base.split_off_left(),
+            captured: self.captured,
+        }
+    }
+
+    fn to_reducer(&self) -> Self::Reducer {
+        ContextReducer {
+            base: self.base.to_reducer(),
+            captured: self.captured,
+        }
+    }
+}
+
+impl<T, B: Folder<T>, C: Install> Folder<T> for ContextFolder<B, C> {
+    type Result = B::Result;
+
+    fn consume(mut self, item: T) -> Self {
+        let base = self.base;
+        ContextFolder {
+            base: self.captured.lend(|| base.consume(item)),
+            captured: self.captured,
+        }
+    }
+
+    fn consume_iter<I: IntoIterator<Item = T>>(mut self, iter: I) -> Self {
+        let base = self.base;
+        ContextFolder {
+            base: self.captured.lend(|| base.consume_iter(iter)),
+            captured: self.captured,
+        }
+    }
+
+    fn complete(self) -> Self::Result {
+        let base = self.base;
+        self.captured.install(|| base.complete())
+    }
+
+    fn full(&self) -> bool { self.base.full() }
+}
+
+impl<R, B: Reducer<R>, C: Install + Clone> Reducer<R> for ContextReducer<'_, B, C> {
+    fn reduce(self, left: R, right: R) -> R { self.captured.clone().install(|| self.base.reduce(left, right)) }
+}
+
+#[test]
+fn with_context() {
+    use crate::{Context, SyncContext};
+    use rayon::prelude::*;
+
+    thread_local! {
+        static NAMES: Context<&'static str> = Context::new(2);
+    }
+
+    static OFFSET: SyncContext<u64> = SyncContext::new(2);
+
+    push!(let _a: NAMES = "a");
+    push!(let _b: NAMES = "b");
+    push!(let _offset: OFFSET = 100);
+
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+
+    // the contexts are captured on this thread, not inside the pool
+    let values = (0..1000u64).into_par_iter().with_context((&NAMES, &OFFSET));
+
+    let sum = pool.install(move || {
+        values
+            .filter(|_| {
+                get_all!(let names: NAMES);
+                names.copied().eq(["b", "a"].iter().copied())
+            })
+            .map(|x| {
+                get!(let offset: OFFSET);
+                x + *offset
+            })
+            .reduce(
+                || 0,
+                |a, b| {
+                    get!(let offset: OFFSET);
+                    assert_eq!(*offset, 100);
+                    a + b
+                },
+            )
+    });
+
+    assert_eq!(sum, (0..1000).map(|x| x + 100).sum::<u64>());
+
+    pool.broadcast(|_| {
+        assert!(crate::ContextExt::is_empty(&NAMES));
+        assert!(crate::ContextExt::is_empty(&OFFSET));
+    });
+}
@@ -0,0 +1,441 @@
+use crate::{with_context_or, Context, ContextExt, ContextKey, Iter, StackPin, SyncContext};
+use std::{
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    task::{self, Poll},
+    thread::{JoinHandle, LocalKey},
+};
+
+/// A copy of all values in a context, from the bottom of the stack to the top
+///
+/// Created by [`Context::snapshot`]
+#[derive(Clone)]
+pub struct Snapshot<T> {
+    values: Vec<T>,
+}
+
+/// A snapshot of a thread local context, which can be installed on another thread
+///
+/// Created by [`Capture::capture`]
+pub struct Captured<K: 'static, T, const N: usize> {
+    key: &'static K,
+    snapshot: Snapshot<T>,
+    context: PhantomData<fn() -> Context<T, N>>,
+}
+
+/// Contexts whose values can be captured and installed on another thread
+///
+/// This is implemented for thread local contexts, [`SyncContext`]s, and tuples of them
+pub trait Capture {
+    type Captured: Install;
+
+    fn capture(self) -> Self::Captured;
+}
+
+/// Values which can be installed onto the current thread's contexts
+pub trait Install: Sized {
+    /// Pushes all captured values onto their contexts for the duration of `f`
+    fn install<R>(self, f: impl FnOnce() -> R) -> R;
+
+    /// Moves all captured values onto their contexts for the duration of `f`,
+    /// then moves them back
+    fn lend<R>(&mut self, f: impl FnOnce() -> R) -> R;
+
+    /// Moves the captured values into the contexts owned by a task
+    #[cfg(feature = "tokio")]
+    #[doc(hidden)]
+    fn into_task(self, task: &mut crate::tokio::TaskContexts);
+
+    /// Installs the captured values whenever `future` is polled
+    fn scope_future<F: Future>(self, future: F) -> InstallFuture<Self, F> {
+        InstallFuture { captured: self, future }
+    }
+}
+
+/// A future which has captured values installed whenever it is polled
+///
+/// Created by [`Install::scope_future`]
+pub struct InstallFuture<I, F> {
+    captured: I,
+    future: F,
+}
+
+/// Spawns a new thread with the current values of `contexts` installed
+///
+/// The values are cloned, so contexts of `Arc`s will share their values with the new thread
+///
+/// ```
+/// # use contextual::{get, push, Context};
+/// thread_local! {
+///     static REQUEST_ID: Context<u64> = Context::new(16);
+/// }
+///
+/// push!(let _id: REQUEST_ID = 10);
+///
+/// let thread = contextual::spawn(&REQUEST_ID, || {
+///     get!(let id: REQUEST_ID);
+///     *id
+/// });
+///
+/// assert_eq!(thread.join().unwrap(), 10);
+/// ```
+pub fn spawn<C, F, R>(contexts: C, f: F) -> JoinHandle<R>
+where
+    C: Capture,
+    C::Captured: Send + 'static,
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let captured = contexts.capture();
+    std::thread::spawn(move || captured.install(f))
+}
+
+impl<I, F: Unpin> Unpin for InstallFuture<I, F> {}
+
+impl<I: Install, F: Future> Future for InstallFuture<I, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        // the captured values are never pinned, they are moved in and out of their contexts on every poll
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        this.captured.lend(move || future.poll(cx))
+    }
+}
+
+impl<T: Clone, const N: usize> Context<T, N> {
+    /// Clones all values in the context
+    ///
+    /// # Panics
+    ///
+    /// If any value in the context is mutably borrowed
+    pub fn snapshot(&self) -> Snapshot<T> {
+        let stack_pin = unsafe { StackPin::new() };
+        let values = unsafe { Iter::from_ref(self, &stack_pin) };
+        Snapshot {
+            values: values.rev().cloned().collect(),
+        }
+    }
+
+    /// Replaces all values in the context with the values in `snapshot` for the
+    /// duration of `f`, the original values are restored afterwards
+    ///
+    /// The original values are hidden, not moved, so they can't be accessed until `f` returns.
+    /// The default value is hidden too, since it may still be borrowed outside of `f`
+    pub fn restore<R>(&self, snapshot: &Snapshot<T>, f: impl FnOnce() -> R) -> R {
+        struct Restore<'a, T, const N: usize> {
+            ctx: &'a Context<T, N>,
+            base: usize,
+            borrow: isize,
+            mut_borrows: usize,
+            default_hidden: bool,
+        }
+
+        impl<T, const N: usize> Drop for Restore<'_, T, N> {
+            fn drop(&mut self) {
+                self.ctx.base.set(self.base);
+                self.ctx.borrow.top.set(self.borrow);
+                self.ctx.borrow.mut_borrows.set(self.mut_borrows);
+                self.ctx.default_hidden.set(self.default_hidden);
+            }
+        }
+
+        let _restore = Restore {
+            ctx: self,
+            base: self.base.replace(self.len.get()),
+            borrow: self.borrow.top.replace(0),
+            mut_borrows: self.borrow.mut_borrows.replace(0),
+            default_hidden: self.default_hidden.replace(true),
+        };
+
+        self.scope_all(snapshot.values.iter().cloned(), f)
+    }
+}
+
+impl<T> Snapshot<T> {
+    pub fn len(&self) -> usize { self.values.len() }
+
+    pub fn is_empty(&self) -> bool { self.values.is_empty() }
+
+    /// The captured values, from the bottom of the stack to the top
+    pub fn values(&self) -> &[T] { &self.values }
+
+    /// Pushes all values onto `context` for the duration of `f`
+    ///
+    /// If `context` has been destroyed, `f` is called without the values
+    pub fn scope<K: ContextKey<T, N>, R, const N: usize>(self, context: &'static K, f: impl FnOnce() -> R) -> R {
+        with_context_or(context, f, move |ctx, f| ctx.scope_all(self.values, f), |f| f())
+    }
+}
+
+impl<T> Default for Snapshot<T> {
+    fn default() -> Self { Self { values: Vec::new() } }
+}
+
+impl<K, T: Clone, const N: usize> Clone for Captured<K, T, N> {
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key,
+            snapshot: self.snapshot.clone(),
+            context: PhantomData,
+        }
+    }
+}
+
+impl<K: ContextKey<T, N>, T: Clone + 'static, const N: usize> Install for Captured<K, T, N> {
+    fn install<R>(self, f: impl FnOnce() -> R) -> R { self.snapshot.scope(self.key, f) }
+
+    fn lend<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        let values = &mut self.snapshot.values;
+        with_context_or(self.key, f, move |ctx, f| ctx.lend_all(values, f), |f| f())
+    }
+
+    #[cfg(feature = "tokio")]
+    fn into_task(self, task: &mut crate::tokio::TaskContexts) {
+        let (key, values) = (self.key, self.snapshot.values);
+        let _ = key.try_with_context(move |ctx| {
+            let ctx = ctx.new_like();
+            for value in values {
+                ctx.push(value);
+            }
+            task.insert(key, ctx);
+        });
+    }
+}
+
+impl<T: Clone + 'static, const N: usize> Capture for &'static LocalKey<Context<T, N>> {
+    type Captured = Captured<LocalKey<Context<T, N>>, T, N>;
+
+    fn capture(self) -> Self::Captured {
+        Captured {
+            key: self,
+            snapshot: ContextExt::snapshot(self),
+            context: PhantomData,
+        }
+    }
+}
+
+impl<T: Clone + 'static, const N: usize> Capture for &'static SyncContext<T, N> {
+    type Captured = Captured<SyncContext<T, N>, T, N>;
+
+    fn capture(self) -> Self::Captured {
+        Captured {
+            key: self,
+            snapshot: ContextExt::snapshot(self),
+            context: PhantomData,
+        }
+    }
+}
+
+impl Capture for () {
+    type Captured = ();
+
+    fn capture(self) -> Self::Captured {}
+}
+
+impl Install for () {
+    fn install<R>(self, f: impl FnOnce() -> R) -> R { f() }
+
+    fn lend<R>(&mut self, f: impl FnOnce() -> R) -> R { f() }
+
+    #[cfg(feature = "tokio")]
+    fn into_task(self, _: &mut crate::tokio::TaskContexts) {}
+}
+
+macro_rules! tuple {
+    ($($name:ident)*) => {
+        impl<$($name: Capture),*> Capture for ($($name,)*) {
+            type Captured = ($($name::Captured,)*);
+
+            #[allow(non_snake_case)]
+            fn capture(self) -> Self::Captured {
+                let ($($name,)*) = self;
+                ($($name.capture(),)*)
+            }
+        }
+
+        impl<$($name: Install),*> Install for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn install<R>(self, f: impl FnOnce() -> R) -> R {
+                let ($($name,)*) = self;
+                tuple!(@install install f $($name)*)
+            }
+
+            #[allow(non_snake_case)]
+            fn lend<R>(&mut self, f: impl FnOnce() -> R) -> R {
+                let ($($name,)*) = self;
+                tuple!(@install lend f $($name)*)
+            }
+
+            #[cfg(feature = "tokio")]
+            #[allow(non_snake_case)]
+            fn into_task(self, task: &mut crate::tokio::TaskContexts) {
+                let ($($name,)*) = self;
+                $($name.into_task(task);)*
+            }
+        }
+    };
+    (@install $method:ident $f:ident) => { $f() };
+    (@install $method:ident $f:ident $first:ident $($rest:ident)*) => {
+        $first.$method(move || tuple!(@install $method $f $($rest)*))
+    };
+}
+
+tuple!(A);
+tuple!(A B);
+tuple!(A B C);
+tuple!(A B C D);
+tuple!(A B C D E);
+tuple!(A B C D E F);
+
+#[test]
+fn spawn_with_context() {
+    use crate::ContextExt;
+
+    thread_local! {
+        static NAMES: Context<&'static str> = Context::new(2);
+    }
+
+    static COUNTS: SyncContext<std::sync::Arc<std::sync::atomic::AtomicUsize>> = SyncContext::new(2);
+
+    let count = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    NAMES.scope("a", |_| {
+        push!(let _b: NAMES = "b");
+        push!(let _c: NAMES = "c");
+        push!(let _count: COUNTS = count.clone());
+
+        let thread = spawn((&NAMES, &COUNTS), || {
+            get_all!(let names: NAMES);
+            assert!(names.copied().eq(["c", "b", "a"].iter().copied()));
+            get!(let count: COUNTS);
+            count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            NAMES.len()
+        });
+
+        assert_eq!(thread.join().unwrap(), 3);
+    });
+
+    assert_eq!(count.load(std::sync::atomic::Ordering::Relaxed), 1);
+    assert_eq!(std::sync::Arc::strong_count(&count), 1);
+
+    let thread = spawn(&NAMES, || NAMES.is_empty());
+    assert!(thread.join().unwrap());
+}
+
+#[test]
+fn snapshot_scope() {
+    thread_local! {
+        static CONTEXT: Context<i32> = Context::new(2);
+    }
+
+    let snapshot = Snapshot {
+        values: vec![1, 2, 3],
+    };
+
+    snapshot.scope(&CONTEXT, || {
+        {
+            get_all!(let values: CONTEXT);
+            assert!(values.copied().eq([3, 2, 1].iter().copied()));
+        }
+
+        get_mut!(let top: CONTEXT);
+        *top = 4;
+    });
+
+    try_get!(let value: CONTEXT);
+    assert_eq!(value, Err(crate::ContextError::Empty));
+
+    CONTEXT.with(|ctx| {
+        ctx.push(0);
+        let snapshot = ctx.snapshot();
+        assert_eq!(snapshot.values(), [0]);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| ctx.scope_all(vec![1, 2, 3], || panic!())));
+        assert!(result.is_err());
+        assert_eq!(ctx.len(), 1);
+    });
+}
+
+#[test]
+fn restore() {
+    use crate::ContextExt;
+
+    thread_local! {
+        static CONTEXT: Context<String> = Context::new(2);
+    }
+
+    fn all() -> Vec<String> {
+        get_all!(let values: CONTEXT);
+        values.cloned().collect()
+    }
+
+    let mut deferred = Vec::new();
+
+    CONTEXT.scope("request 1".to_string(), |_| {
+        push!(let _span: CONTEXT = "span".to_string());
+        deferred.push(CONTEXT.snapshot());
+    });
+
+    CONTEXT.scope("request 2".to_string(), |_| {
+        deferred.push(CONTEXT.snapshot());
+    });
+
+    assert!(CONTEXT.is_empty());
+
+    CONTEXT.lend(&mut Some("worker".to_string()), || {
+        get_mut!(let _worker: CONTEXT);
+
+        for _ in 0..2 {
+            CONTEXT.restore(&deferred[0], || {
+                assert_eq!(all(), ["span", "request 1"]);
+                assert_eq!(CONTEXT.len(), 2);
+                CONTEXT.restore(&deferred[1], || assert_eq!(all(), ["request 2"]));
+                CONTEXT.restore(&Snapshot { values: Vec::new() }, || {
+                    try_get!(let value: CONTEXT);
+                    assert_eq!(value, Err(crate::ContextError::Empty));
+                    assert!(all().is_empty());
+                });
+
+                {
+                    get_mut!(let top: CONTEXT);
+                    top.push_str(" (modified)");
+                }
+
+                assert_eq!(all(), ["span (modified)", "request 1"]);
+            });
+        }
+
+        assert_eq!(CONTEXT.len(), 1);
+    });
+
+    assert_eq!(all(), Vec::<String>::new());
+}
+
+#[test]
+fn install_future() {
+    thread_local! {
+        static CONTEXT: Context<String> = Context::new(2);
+    }
+
+    CONTEXT.with(|ctx| ctx.push("main".to_string()));
+
+    let future = (&CONTEXT,).capture().scope_future(async {
+        for _ in 0..3 {
+            {
+                get_all!(let values: CONTEXT);
+                assert!(values.map(String::as_str).eq(["main"].iter().copied()));
+            }
+            crate::YieldNow(false).await;
+        }
+
+        get_mut!(let value: CONTEXT);
+        value.push_str(" (modified)");
+    });
+
+    crate::block_on_threads(future);
+
+    get!(let value: CONTEXT);
+    assert_eq!(value, "main");
+}
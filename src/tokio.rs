@@ -0,0 +1,321 @@
+//! Carry contexts into tokio tasks
+//!
+//! [`spawn`] and [`scope`] capture the current values of contexts into a `tokio::task_local!`
+//! owned by the task. While the task is polled, `get!`, `push!` and the other macros use the
+//! task's contexts before the thread's, so values pushed inside the task stay with it across
+//! `.await`s, on whichever worker the task happens to run.
+//!
+//! Like tokio's own task locals, tasks spawned with plain `tokio::spawn` don't inherit them
+//!
+//! ```
+//! use contextual::{get, push, Context};
+//!
+//! thread_local! {
+//!     static REQUEST_ID: Context<u64> = Context::new(16);
+//! }
+//!
+//! let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+//!
+//! runtime.block_on(async {
+//!     let task = {
+//!         push!(let _id: REQUEST_ID = 10);
+//!
+//!         contextual::tokio::spawn(&REQUEST_ID, async {
+//!             tokio::task::yield_now().await;
+//!             get!(let id: REQUEST_ID);
+//!             *id
+//!         })
+//!     };
+//!
+//!     assert_eq!(task.await.unwrap(), 10);
+//! });
+//! ```
+
+use crate::{Capture, Context, ContextKey, Install};
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    future::Future,
+};
+use tokio::task::{futures::TaskLocalFuture, JoinHandle};
+
+tokio::task_local! {
+    static TASK_CONTEXTS: TaskContexts;
+}
+
+thread_local! {
+    static THREAD: u8 = const { 0 };
+}
+
+/// The contexts owned by a task
+///
+/// Created by [`scope`]
+pub struct TaskContexts {
+    contexts: Vec<TaskContext>,
+    /// the index of each context, by the [`ContextKey::task_key`] of its key on `thread`
+    keys: RefCell<HashMap<usize, usize>>,
+    thread: Cell<usize>,
+}
+
+struct TaskContext {
+    key: Box<dyn Fn() -> Option<usize>>,
+    ctx: Box<dyn Any>,
+}
+
+// the contexts are only created by `scope` from captures which are `Send`,
+// and are only accessed by the thread which is polling the task
+unsafe impl Send for TaskContexts {}
+
+fn current_thread() -> usize { THREAD.try_with(|thread| thread as *const u8 as usize).unwrap_or(0) }
+
+impl TaskContexts {
+    pub(crate) fn insert<K, T, const N: usize>(&mut self, key: &'static K, ctx: Context<T, N>)
+    where
+        K: ContextKey<T, N>,
+        T: 'static,
+    {
+        if let Some(task_key) = key.task_key() {
+            self.keys.get_mut().insert(task_key, self.contexts.len());
+            self.contexts.push(TaskContext {
+                key: Box::new(move || key.task_key()),
+                ctx: Box::new(ctx),
+            });
+        }
+    }
+
+    fn get<T: 'static, const N: usize>(&self, task_key: usize) -> Option<&Context<T, N>> {
+        // thread local keys are identified by their storage, which is different on every thread
+        let thread = current_thread();
+        if self.thread.replace(thread) != thread {
+            let keys = self.contexts.iter().enumerate();
+            *self.keys.borrow_mut() = keys.filter_map(|(i, ctx)| Some(((ctx.key)()?, i))).collect();
+        }
+
+        let index = *self.keys.borrow().get(&task_key)?;
+        self.contexts[index].ctx.downcast_ref()
+    }
+}
+
+/// Calls `f` with the current task's context for `key`, or returns `f` if the task doesn't have one
+pub(crate) fn with_task_context<K, T, F, R, const N: usize>(key: &'static K, f: F) -> Result<R, F>
+where
+    K: ContextKey<T, N> + ?Sized,
+    T: 'static,
+    F: FnOnce(&Context<T, N>) -> R,
+{
+    let ctx = TASK_CONTEXTS.try_with(|task| Some(task.get::<T, N>(key.task_key()?)? as *const Context<T, N>));
+
+    match ctx {
+        // contexts are boxed and owned by the task's future, so they outlive every value pushed inside of it
+        Ok(Some(ctx)) => Ok(f(unsafe { &*ctx })),
+        _ => Err(f),
+    }
+}
+
+/// Spawns a new task which owns the current values of `contexts`
+///
+/// # Panics
+///
+/// If called outside of a tokio runtime
+pub fn spawn<C, F>(contexts: C, future: F) -> JoinHandle<F::Output>
+where
+    C: Capture,
+    C::Captured: Send,
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(scope(contexts, future))
+}
+
+/// Runs `f` on tokio's blocking thread pool with the current values of `contexts` installed
+///
+/// # Panics
+///
+/// If called outside of a tokio runtime
+pub fn spawn_blocking<C, F, R>(contexts: C, f: F) -> JoinHandle<R>
+where
+    C: Capture,
+    C::Captured: Send + 'static,
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let captured = contexts.capture();
+    tokio::task::spawn_blocking(move || captured.install(f))
+}
+
+/// Captures the current values of `contexts` into task locals which are set whenever `future` is polled
+///
+/// This is useful to carry contexts into other spawning functions, like `tokio::task::spawn_local`.
+/// Inside of `future`, only `contexts` are task locals, even if it's polled by a task with other contexts
+pub fn scope<C, F>(contexts: C, future: F) -> TaskLocalFuture<TaskContexts, F>
+where
+    C: Capture,
+    C::Captured: Send,
+    F: Future,
+{
+    let mut task = TaskContexts {
+        contexts: Vec::new(),
+        keys: RefCell::new(HashMap::new()),
+        thread: Cell::new(current_thread()),
+    };
+    contexts.capture().into_task(&mut task);
+    TASK_CONTEXTS.scope(task, future)
+}
+
+#[test]
+fn spawn_with_context() {
+    use crate::{Context, ContextExt};
+
+    thread_local! {
+        static NAMES: Context<String> = Context::new(2);
+    }
+
+    fn names() -> Vec<String> {
+        get_all!(let names: NAMES);
+        names.cloned().collect()
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+    runtime.block_on(async {
+        let tasks = ["a", "b"]
+            .iter()
+            .map(|&name| {
+                push!(let _outer: NAMES = "outer".to_string());
+                push!(let _name: NAMES = name.to_string());
+
+                spawn(&NAMES, async move {
+                    let mut expected = name.to_string();
+
+                    for i in 0..3 {
+                        assert_eq!(names(), [&*expected, "outer"]);
+                        expected.push_str(&i.to_string());
+
+                        {
+                            get_mut!(let top: NAMES);
+                            top.push_str(&i.to_string());
+                        }
+
+                        tokio::task::yield_now().await;
+                    }
+
+                    names()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        assert!(NAMES.is_empty());
+
+        let expected = [["a012", "outer"], ["b012", "outer"]];
+        for (task, expected) in tasks.into_iter().zip(expected.iter()) {
+            assert_eq!(task.await.unwrap(), expected);
+        }
+
+        push!(let _name: NAMES = "blocking".to_string());
+        let task = spawn_blocking(&NAMES, names);
+        assert_eq!(task.await.unwrap(), ["blocking"]);
+    });
+
+    assert!(NAMES.is_empty());
+}
+
+#[test]
+fn task_local_values() {
+    use crate::ContextExt;
+
+    thread_local! {
+        static NAMES: Context<&'static str> = Context::new(2).with_default("default");
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let local = tokio::task::LocalSet::new();
+
+    local.block_on(&runtime, async {
+        let tasks = ["a", "b"]
+            .iter()
+            .map(|&name| {
+                push!(let _name: NAMES = name);
+
+                tokio::task::spawn_local(scope(&NAMES, async {
+                    // the pushed value stays with the task while other tasks run
+                    push!(let _inner: NAMES = "inner");
+                    tokio::task::yield_now().await;
+
+                    get_all!(let names: NAMES);
+                    names.copied().collect::<Vec<_>>()
+                }))
+            })
+            .collect::<Vec<_>>();
+
+        for (task, name) in tasks.into_iter().zip(["a", "b"].iter()) {
+            assert_eq!(task.await.unwrap(), ["inner", *name]);
+        }
+
+        let task = tokio::task::spawn_local(scope(&NAMES, async {
+            get!(let name: NAMES);
+            *name
+        }));
+        assert_eq!(task.await.unwrap(), "default");
+
+        assert!(NAMES.is_empty());
+    });
+}
+
+#[test]
+fn local_context_in_task() {
+    crate::context! {
+        static NAME: String;
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+    runtime.block_on(async {
+        let _outer = NAME.push("outer".to_string());
+
+        let task = spawn(NAME.key(), async {
+            let _inner = NAME.push("inner".to_string());
+            (NAME.get(String::clone), NAME.len())
+        });
+        assert_eq!(task.await.unwrap(), ("inner".to_string(), 2));
+
+        // the value was pushed onto the task's context, not the thread's
+        assert_eq!(NAME.get(String::clone), "outer");
+        assert_eq!(NAME.len(), 1);
+    });
+}
+
+#[test]
+fn task_moves_between_threads() {
+    use crate::{SyncContext, YieldNow};
+
+    thread_local! {
+        static NAME: Context<String> = Context::new(2);
+    }
+
+    static DEPTH: SyncContext<u32> = SyncContext::new(2);
+
+    push!(let _name: NAME = "main".to_string());
+    push!(let _depth: DEPTH = 1);
+
+    let future = scope((&NAME, &DEPTH), async {
+        for i in 0..3 {
+            {
+                get_mut!(let name: NAME);
+                name.push_str(&i.to_string());
+                get!(let depth: DEPTH);
+                assert_eq!(*depth, 1);
+            }
+            YieldNow(false).await;
+        }
+
+        get!(let name: NAME);
+        name.clone()
+    });
+
+    // every poll is on a new thread
+    assert_eq!(crate::block_on_threads(future), "main012");
+
+    get!(let name: NAME);
+    assert_eq!(*name, "main");
+}
@@ -0,0 +1,349 @@
+//! Record context values onto tracing spans and events
+//!
+//! [`ContextLayer`] captures the top value of each registered context whenever a
+//! span is created, and stores them in the span's [`ContextFields`] extension.
+//! To log the values, wrap the fmt layer's field formatter with [`ContextLayer::fmt_fields`],
+//! which appends them to the fields of every span when it's created, and of every event.
+//!
+//! ```
+//! use contextual::{push, tracing::ContextLayer, Context};
+//! use tracing_subscriber::{fmt::format::DefaultFields, prelude::*};
+//!
+//! thread_local! {
+//!     static REQUEST_ID: Context<u64> = Context::new(16);
+//! }
+//!
+//! let contexts = ContextLayer::new().with_context("request_id", &REQUEST_ID);
+//! let subscriber = tracing_subscriber::registry()
+//!     .with(tracing_subscriber::fmt::layer().fmt_fields(contexts.fmt_fields(DefaultFields::new())))
+//!     .with(contexts);
+//!
+//! tracing::subscriber::with_default(subscriber, || {
+//!     push!(let _id: REQUEST_ID = 10);
+//!
+//!     // logged as `handle{request_id=10}: started request_id=10`
+//!     let _span = tracing::info_span!("handle").entered();
+//!     tracing::info!("started");
+//! });
+//! ```
+
+use crate::{Context, ContextKey};
+use std::{fmt, sync::Arc};
+use tracing_core::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Subscriber,
+};
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{
+        format::{FormatFields, Writer},
+        FormattedFields,
+    },
+    layer::{self, Layer},
+    registry::LookupSpan,
+};
+
+/// Values which can be recorded onto spans
+///
+/// Implemented for primitives and strings, other `Debug` types can be recorded with
+/// [`ContextLayer::with_debug_context`]. There is no blanket impl for `Debug` types,
+/// so values can choose a different format
+pub trait ContextValue {
+    /// Formats the value as it should appear in the span's fields
+    fn record(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+macro_rules! debug_value {
+    ($($type:ty),*) => {$(
+        impl ContextValue for $type {
+            fn record(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { fmt::Debug::fmt(self, f) }
+        }
+    )*};
+}
+
+debug_value!(bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, str, String);
+
+impl<T: ContextValue + ?Sized> ContextValue for &T {
+    fn record(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { T::record(self, f) }
+}
+
+impl<T: ContextValue + ?Sized> ContextValue for Box<T> {
+    fn record(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { T::record(self, f) }
+}
+
+impl<T: ContextValue + ?Sized> ContextValue for Arc<T> {
+    fn record(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { T::record(self, f) }
+}
+
+type Recorder = Arc<dyn Fn() -> Option<String> + Send + Sync>;
+
+type Format<T> = fn(&T, &mut fmt::Formatter<'_>) -> fmt::Result;
+
+/// A layer which records the top values of contexts onto every new span
+///
+/// The values are available to other layers through the span's [`ContextFields`] extension,
+/// and can be logged with [`fmt_fields`](Self::fmt_fields)
+#[derive(Clone, Default)]
+pub struct ContextLayer {
+    contexts: Vec<(&'static str, Recorder)>,
+}
+
+/// Formats fields with `N`, then appends the current top values of the contexts
+///
+/// Created by [`ContextLayer::fmt_fields`]
+pub struct ContextFormatFields<N> {
+    inner: N,
+    contexts: Vec<(&'static str, Recorder)>,
+}
+
+/// The context values which were recorded when a span was created
+///
+/// Stored in the extensions of every span created while a [`ContextLayer`] is active
+pub struct ContextFields {
+    fields: Vec<(&'static str, String)>,
+}
+
+struct Value<'a, T>(&'a T, Format<T>);
+
+impl<T> fmt::Debug for Value<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { (self.1)(self.0, f) }
+}
+
+fn record<K: ContextKey<T, N>, T, const N: usize>(context: &'static K, format: Format<T>) -> Option<String> {
+    let value = context.try_with_context(|ctx| {
+        // skip values which are mutably borrowed, instead of panicking in the middle of creating a span
+        let top = ctx.top().filter(|_| ctx.borrow.top.get() >= 0)?;
+
+        ctx.borrow.borrow();
+        let _release = Release(ctx);
+        Some(format!("{:?}", Value(unsafe { top.as_ref() }, format)))
+    });
+
+    value.ok().flatten()
+}
+
+fn record_all(contexts: &[(&'static str, Recorder)]) -> Vec<(&'static str, String)> {
+    contexts.iter().filter_map(|(name, record)| Some((*name, record()?))).collect()
+}
+
+struct Release<'a, T, const N: usize>(&'a Context<T, N>);
+
+impl<T, const N: usize> Drop for Release<'_, T, N> {
+    fn drop(&mut self) { self.0.borrow.release() }
+}
+
+struct CountFields(usize);
+
+impl Visit for CountFields {
+    fn record_debug(&mut self, _: &Field, _: &dyn fmt::Debug) { self.0 += 1 }
+}
+
+impl ContextLayer {
+    pub fn new() -> Self { Self::default() }
+
+    /// Records the top value of `context` as the field `name`
+    pub fn with_context<K, T, const N: usize>(self, name: &'static str, context: &'static K) -> Self
+    where
+        K: ContextKey<T, N> + Sync,
+        T: ContextValue + 'static,
+    {
+        self.with_format(name, context, T::record)
+    }
+
+    /// Records the top value of `context` as the field `name`, formatted with `Debug`
+    pub fn with_debug_context<K, T, const N: usize>(self, name: &'static str, context: &'static K) -> Self
+    where
+        K: ContextKey<T, N> + Sync,
+        T: fmt::Debug + 'static,
+    {
+        self.with_format(name, context, T::fmt)
+    }
+
+    fn with_format<K, T, const N: usize>(mut self, name: &'static str, context: &'static K, format: Format<T>) -> Self
+    where
+        K: ContextKey<T, N> + Sync,
+        T: 'static,
+    {
+        self.contexts.push((name, Arc::new(move || record(context, format))));
+        self
+    }
+
+    /// Wraps a field formatter of the fmt layer, so the context values are appended to the
+    /// fields of every span when it's created, and of every event when it's recorded
+    ///
+    /// Events inside a span show both the values from when the span was created and the current values
+    pub fn fmt_fields<N>(&self, inner: N) -> ContextFormatFields<N> {
+        ContextFormatFields {
+            inner,
+            contexts: self.contexts.clone(),
+        }
+    }
+}
+
+impl ContextFields {
+    /// The recorded field names and values
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        self.fields.iter().map(|(name, value)| (*name, value.as_str()))
+    }
+
+    /// The recorded value of the field `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.iter().find(|&(field, _)| field == name).map(|(_, value)| value)
+    }
+}
+
+impl<S> Layer<S> for ContextLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _: &Attributes<'_>, id: &Id, ctx: layer::Context<'_, S>) {
+        let fields = record_all(&self.contexts);
+        if fields.is_empty() {
+            return
+        }
+
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(ContextFields { fields });
+        }
+    }
+}
+
+impl<'writer, N> FormatFields<'writer> for ContextFormatFields<N>
+where
+    N: for<'w> FormatFields<'w> + 'static,
+{
+    fn format_fields<R: RecordFields>(&self, mut writer: Writer<'writer>, fields: R) -> fmt::Result {
+        let mut count = CountFields(0);
+        fields.record(&mut count);
+        self.inner.format_fields(writer.by_ref(), &fields)?;
+
+        for (i, (name, value)) in record_all(&self.contexts).into_iter().enumerate() {
+            if count.0 + i > 0 {
+                writer.write_char(' ')?;
+            }
+            write!(writer, "{}={}", name, value)?;
+        }
+
+        Ok(())
+    }
+
+    // the context values were already appended when the span was created
+    fn add_fields(&self, current: &'writer mut FormattedFields<Self>, fields: &Record<'_>) -> fmt::Result {
+        if !current.fields.is_empty() {
+            current.fields.push(' ');
+        }
+        self.inner.format_fields(current.as_writer(), fields)
+    }
+}
+
+#[cfg(test)]
+fn capture_logs(layer: ContextLayer, f: impl FnOnce()) -> String {
+    use std::sync::Mutex;
+    use tracing_subscriber::{fmt::format::DefaultFields, prelude::*};
+
+    #[derive(Clone)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    let output = Output(Arc::default());
+    let writer = output.clone();
+
+    let subscriber = tracing_subscriber::registry().with(
+        tracing_subscriber::fmt::layer()
+            .fmt_fields(layer.fmt_fields(DefaultFields::new()))
+            .without_time()
+            .with_target(false)
+            .with_ansi(false)
+            .with_writer(move || writer.clone()),
+    );
+
+    tracing::subscriber::with_default(subscriber.with(layer), f);
+
+    let output = output.0.lock().unwrap();
+    String::from_utf8(output.clone()).unwrap()
+}
+
+#[test]
+fn context_layer() {
+    use crate::{ContextExt, SyncContext};
+
+    thread_local! {
+        static REQUEST_ID: Context<String> = Context::new(2);
+    }
+
+    static USER: SyncContext<u32> = SyncContext::new(2);
+
+    let layer = ContextLayer::new()
+        .with_context("request_id", &REQUEST_ID)
+        .with_context("user", &USER);
+
+    let logs = capture_logs(layer, || {
+        tracing::info!("outside");
+
+        push!(let _id: REQUEST_ID = "abc".to_string());
+        tracing::info!("pushed");
+        let span = tracing::info_span!("request", method = "GET", status = tracing::field::Empty);
+
+        {
+            push!(let _user: USER = 7);
+            let _span = tracing::info_span!("auth").entered();
+            tracing::info!("authenticated");
+
+            REQUEST_ID.lend(&mut Some("def".to_string()), || {
+                get_mut!(let _id: REQUEST_ID);
+                let _span = tracing::info_span!("borrowed").entered();
+                tracing::info!("skipped");
+            });
+        }
+
+        span.record("status", 200);
+        let _span = span.entered();
+        tracing::info!("handled");
+    });
+
+    let lines = logs.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].ends_with(" outside"));
+    assert!(lines[1].ends_with(r#" pushed request_id="abc""#));
+    assert!(lines[2].ends_with(r#" auth{request_id="abc" user=7}: authenticated request_id="abc" user=7"#));
+    assert!(lines[3].ends_with(r#" auth{request_id="abc" user=7}:borrowed{user=7}: skipped user=7"#));
+    assert!(lines[4].ends_with(r#" request{method="GET" request_id="abc" status=200}: handled request_id="abc""#));
+}
+
+#[test]
+fn context_fields() {
+    use tracing_subscriber::{prelude::*, registry::Registry};
+
+    // `Option` only implements `Debug`, not `ContextValue`
+    thread_local! {
+        static LOCALE: Context<Option<&'static str>> = Context::new(2);
+    }
+
+    let subscriber = tracing_subscriber::registry().with(ContextLayer::new().with_debug_context("locale", &LOCALE));
+
+    tracing::subscriber::with_default(subscriber, || {
+        push!(let _locale: LOCALE = Some("en"));
+
+        // the values are recorded even if the span is never entered
+        let span = tracing::info_span!("unentered");
+        let id = span.id().unwrap();
+
+        tracing::dispatcher::get_default(|dispatch| {
+            let registry = dispatch.downcast_ref::<Registry>().unwrap();
+            let span = registry.span(&id).unwrap();
+            let extensions = span.extensions();
+            let fields = extensions.get::<ContextFields>().unwrap();
+            assert_eq!(fields.get("locale"), Some(r#"Some("en")"#));
+            assert!(fields.iter().eq([("locale", r#"Some("en")"#)].iter().copied()));
+        });
+    });
+}